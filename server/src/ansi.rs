@@ -0,0 +1,204 @@
+//! ANSI escape sequences, as left in log files by programs that colour their
+//! output.  SGR sequences (`ESC [ ... m`) are turned into styles, and any
+//! others, e.g. cursor movement or OSC hyperlinks, are dropped.
+
+use serde::Serialize;
+use std::borrow::Cow;
+
+pub const ESC: char = '\x1b';
+
+/// 0-7 are the standard colours, 8-15 their bright versions, and 16-255 the
+/// rest of the xterm 256 colour palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Style {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fg: Option<Color>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bg: Option<Color>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bold: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub underline: bool,
+}
+
+impl Style {
+    pub fn is_plain(&self) -> bool {
+        *self == Style::default()
+    }
+
+    /// Apply the parameters of an SGR sequence, e.g. `1;31` for bold red
+    fn apply(&mut self, params: &str) {
+        // ESC [ m is short for ESC [ 0 m, and likewise for empty parameters
+        let mut params = params.split(';').map(|p| p.parse::<u16>().unwrap_or(0));
+        while let Some(p) = params.next() {
+            match p {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                4 => self.underline = true,
+                24 => self.underline = false,
+                30..=37 => self.fg = Some(Color::Indexed(p as u8 - 30)),
+                38 => self.fg = extended_color(&mut params),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(Color::Indexed(p as u8 - 40)),
+                48 => self.bg = extended_color(&mut params),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(Color::Indexed(p as u8 - 90 + 8)),
+                100..=107 => self.bg = Some(Color::Indexed(p as u8 - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The rest of a 38 or 48: `5;n` for a palette colour, `2;r;g;b` for RGB
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    let mut byte = || params.next().and_then(|p| u8::try_from(p).ok());
+    match byte()? {
+        5 => Some(Color::Indexed(byte()?)),
+        2 => Some(Color::Rgb(byte()?, byte()?, byte()?)),
+        _ => None,
+    }
+}
+
+/// Takes escape sequences out of one or more pieces of text, keeping track
+/// of the style across them
+#[derive(Debug, Default)]
+pub struct Stripper {
+    style: Style,
+    /// How much stripped text there's been so far
+    len: usize,
+    styles: Vec<(usize, Style)>,
+}
+
+impl Stripper {
+    /// The next piece of text, stripped
+    pub fn strip(&mut self, s: &str) -> String {
+        let mut ret = String::with_capacity(s.len());
+        let mut chars = s.char_indices().peekable();
+        while let Some((_, c)) = chars.next() {
+            if c != ESC {
+                ret.push(c);
+                continue;
+            }
+            match chars.next() {
+                // CSI: parameters, intermediates, then a final byte
+                Some((i, '[')) => {
+                    let start = i + 1;
+                    let mut end = s.len();
+                    let mut sgr = false;
+                    for (j, c) in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            end = j;
+                            sgr = c == 'm';
+                            break;
+                        }
+                    }
+                    let params = &s[start..end];
+                    if sgr && params.bytes().all(|b| b.is_ascii_digit() || b == b';') {
+                        self.style.apply(params);
+                        self.mark(self.len + ret.len());
+                    }
+                }
+                // OSC: up to BEL or ST (ESC \)
+                Some((_, ']')) => {
+                    while let Some((_, c)) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == ESC && chars.next_if(|&(_, c)| c == '\\').is_some() {
+                            break;
+                        }
+                    }
+                }
+                // nF, e.g. ESC ( B: intermediates, then a final byte
+                Some((_, ' '..='/')) => {
+                    while chars.next_if(|&(_, c)| (' '..='/').contains(&c)).is_some() {}
+                    chars.next();
+                }
+                // anything else is ESC and one more character
+                _ => {}
+            }
+        }
+        self.len += ret.len();
+        ret
+    }
+
+    /// Note that the style is now self.style from offset on
+    fn mark(&mut self, offset: usize) {
+        if let Some((at, _)) = self.styles.last() {
+            if *at == offset {
+                self.styles.pop();
+            }
+        }
+        let last = self.styles.last().map_or(Style::default(), |(_, style)| *style);
+        if last != self.style {
+            self.styles.push((offset, self.style));
+        }
+    }
+
+    /// Where the style changes in the stripped text: each style applies
+    /// from its byte offset up to the next one's
+    pub fn styles(self) -> Vec<(usize, Style)> {
+        self.styles
+    }
+}
+
+/// Strip escape sequences out of s, returning what's left and where the
+/// style changes in it, see Stripper::styles
+pub fn strip(s: &str) -> (Cow<'_, str>, Vec<(usize, Style)>) {
+    if !s.contains(ESC) {
+        return (Cow::Borrowed(s), vec![]);
+    }
+    let mut stripper = Stripper::default();
+    let stripped = stripper.strip(s);
+    (Cow::Owned(stripped), stripper.styles())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let red = Style { fg: Some(Color::Indexed(1)), ..Default::default() };
+        let (s, styles) = strip("\x1b[31mred\x1b[0m plain \x1b[1;4mbold\x1b[m");
+        assert_eq!(s, "red plain bold");
+        let bold = Style { bold: true, underline: true, ..Default::default() };
+        assert_eq!(
+            styles,
+            vec![(0, red), (3, Style::default()), (10, bold), (14, Style::default())]
+        );
+        // extended colours, and changes with nothing between them
+        let (s, styles) = strip("\x1b[38;5;208m\x1b[48;2;0;0;255mx\x1b[39;49m");
+        assert_eq!(s, "x");
+        let style = Style {
+            fg: Some(Color::Indexed(208)),
+            bg: Some(Color::Rgb(0, 0, 255)),
+            ..Default::default()
+        };
+        assert_eq!(styles, vec![(0, style), (1, Style::default())]);
+        // bright colours, and a reset to what it already was
+        let (_, styles) = strip("\x1b[0ma\x1b[91mb\x1b[22mc");
+        let bright = Style { fg: Some(Color::Indexed(9)), ..Default::default() };
+        assert_eq!(styles, vec![(1, bright)]);
+        // other sequences just go
+        let (s, styles) =
+            strip("\x1b[2K\x1b]8;;http://x\x1b\\link\x1b]8;;\x07\x1b(B!\x1b[");
+        assert_eq!((s.as_ref(), styles), ("link!", vec![]));
+        // as does nothing, when there's nothing to strip
+        assert!(matches!(strip("plain"), (Cow::Borrowed("plain"), _)));
+        // style carries over from one piece to the next
+        let mut stripper = Stripper::default();
+        assert_eq!(stripper.strip("a\x1b[31m"), "a");
+        assert_eq!(stripper.strip("b"), "b");
+        assert_eq!(stripper.styles(), vec![(1, red)]);
+    }
+}
@@ -1,7 +1,114 @@
+use crate::s6;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    pub logsets: HashMap<String, PathBuf>,
+    pub logsets: HashMap<String, Logset>,
+}
+
+/// A logset can be given as just a path to a log file, or in full
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "LogsetRepr")]
+pub struct Logset {
+    pub path: PathBuf,
+    pub kind: LogsetKind,
+    /// Detected from the logset's lines if not given
+    pub format: Option<Format>,
+}
+
+impl Logset {
+    /// The file being written to
+    pub fn current(&self) -> PathBuf {
+        match self.kind {
+            LogsetKind::File => self.path.clone(),
+            LogsetKind::S6 => self.path.join(s6::CURRENT),
+        }
+    }
+
+    /// Files that logically precede the current one, oldest first
+    pub fn archives(&self) -> Result<Vec<PathBuf>> {
+        match self.kind {
+            LogsetKind::File => Ok(vec![]),
+            LogsetKind::S6 => s6::archives(&self.path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogsetKind {
+    /// A single log file
+    #[default]
+    File,
+    /// An s6-log directory, `current` plus its rotated archives
+    S6,
+}
+
+/// How to split a logset's lines into timestamp, level, target and message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Format {
+    /// `[ts LEVEL target] msg`, as written by env_logger
+    EnvLogger,
+    Regex(RegexFormat),
+    /// A JSON object per line, e.g. from tracing-subscriber or bunyan
+    Json(JsonFormat),
+    /// `key=value key2="quoted value"` pairs
+    Logfmt,
+    /// RFC 5424 or RFC 3164 syslog, as written by rsyslog
+    Syslog,
+    /// Nothing to parse, all message
+    Plain,
+}
+
+/// A user-defined format, as a regex with named captures `ts`, `level`,
+/// `target` and `msg`, all optional.  Any other named captures are kept as
+/// fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegexFormat {
+    pub regex: String,
+    /// chrono strftime format of the `ts` capture; RFC 3339 if not given
+    pub ts_format: Option<String>,
+    /// What the `level` capture's values mean, if not the usual names
+    #[serde(default)]
+    pub levels: HashMap<String, log::Level>,
+}
+
+/// Where to find the usual fields in a JSON log line, as keys into the
+/// object, dotted for nested objects.  If not given, the common names for
+/// each are tried, e.g. `timestamp`, `time` and `ts`.  Whatever's left is
+/// shown as fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JsonFormat {
+    pub ts_key: Option<String>,
+    pub level_key: Option<String>,
+    pub target_key: Option<String>,
+    pub msg_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogsetRepr {
+    Path(PathBuf),
+    Full {
+        path: PathBuf,
+        #[serde(default)]
+        kind: LogsetKind,
+        #[serde(default)]
+        format: Option<Format>,
+    },
+}
+
+impl From<LogsetRepr> for Logset {
+    fn from(repr: LogsetRepr) -> Self {
+        match repr {
+            LogsetRepr::Path(path) => {
+                Logset { path, kind: LogsetKind::File, format: None }
+            }
+            LogsetRepr::Full { path, kind, format } => Logset { path, kind, format },
+        }
+    }
 }
@@ -1,163 +1,882 @@
 use crate::{
-    config::Config,
+    config::{Config, Format, Logset},
+    feed::{FeedEvent, Feeds, Line, Reader, Rotation, Subscriber},
+    index::Indexes,
     json_rpc,
-    parser::{self, DisplayLine},
+    merge::Merge,
+    parser::{self, DisplayLine, LineFilter, LineParser, ParsedLine, Records, Wrap},
+    query::{Query, QueryError},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures_util::{
+    future::{self, Either},
+    select_biased,
+    stream::SplitSink,
+    FutureExt, SinkExt, StreamExt,
 };
-use anyhow::{anyhow, Result};
-use futures_util::{select_biased, stream::SplitSink, FutureExt, SinkExt, StreamExt};
 use log::{debug, error};
-use notify::{event::EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{future, path::PathBuf, sync::Arc};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
-    sync::watch,
-};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::watch, time::Instant};
 use warp::ws::{Message, WebSocket};
 
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsetInfo {
+    pub name: String,
+    /// None if it couldn't be detected, e.g. the logset doesn't exist yet
+    pub format: Option<Format>,
+    /// Whether the format was detected from the logset's lines, rather
+    /// than configured
+    pub detected: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogsRequest {
+    pub logset: String,
+    #[serde(flatten)]
+    pub options: LogsOptions,
+}
+
+/// Several logsets' lines as one, ordered by timestamp and labelled with
+/// which logset they're from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeRequest {
+    pub logsets: Vec<String>,
+    #[serde(flatten)]
+    pub options: LogsOptions,
+}
+
+/// How to wrap and filter lines, for logs, merge and range requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayOptions {
     pub cols: usize,
+    /// Indent wrapped rows to line up under the message
+    #[serde(default)]
+    pub hanging_indent: bool,
     pub filter: Option<String>,
+    /// Only send records the query matches, e.g. `level>=warn AND /timeout/`
+    pub query: Option<String>,
+}
+
+impl DisplayOptions {
+    /// The filter regex and query, parsed
+    fn line_filter(&self) -> Result<LineFilter> {
+        Ok(LineFilter {
+            regex: self.filter.as_deref().map(filter_regex).transpose()?,
+            query: self.query.as_deref().map(query).transpose()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// How to show logs, for logs and merge requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogsOptions {
+    #[serde(flatten)]
+    pub display: DisplayOptions,
+    /// Only send this much of the existing logset before tailing
+    pub backfill: Option<Backfill>,
+    /// Only send lines timestamped at or after this
+    pub since: Option<DateTime<Utc>>,
+    /// Only send lines timestamped before this
+    pub until: Option<DateTime<Utc>>,
+    /// Only send lines at least this severe, e.g. "warn"
+    pub min_level: Option<log::Level>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backfill {
+    /// The last n logical lines
+    Lines(usize),
+    /// At most the last n bytes, starting on a line boundary
+    Bytes(u64),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsResponse {
+    /// Which subscription the logs' tail, rotated and done notifications
+    /// are for
+    pub subscription: u64,
+    /// If true, logical line numbers count from the start of the backfill
+    /// window rather than from the start of the logset
+    pub lln_relative: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResizeRequest {
+    pub subscription: u64,
+    pub cols: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResizeResponse {
+    /// Replaces the display lines sent so far.  Only the most recent are
+    /// kept to be rewrapped, so on a long tail the earliest may be missing;
+    /// range can fetch them.
+    pub display_lines: Vec<DisplayLine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeRequest {
+    #[serde(flatten)]
+    pub display: DisplayOptions,
     pub logset: String,
+    pub from: RangeFrom,
+    /// How many logical lines to read, at most MAX_RANGE_LINES
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangeFrom {
+    /// Starting at this logical line
+    Lln(usize),
+    /// Starting at the first line at or after this byte offset into the
+    /// logset, e.g. the next cursor from a previous range
+    Cursor(u64),
+    /// Starting at the first line timestamped at or after this time
+    Time(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RangeResponse {
+    pub display_lines: Vec<DisplayLine>,
+    /// Cursor just past the last line read, to page forward from
+    pub next: u64,
+    /// If true, logical line numbers count from the start of the range,
+    /// because the line index doesn't reach that far yet; reading from a
+    /// logical line the index doesn't reach yet gives no lines and true
+    pub lln_relative: bool,
+    /// Size of the logset in bytes
+    pub len: u64,
+    /// Number of logical lines in the logset, once it's all been indexed
+    pub total_lines: Option<usize>,
 }
 
+const MAX_RANGE_LINES: usize = 10_000;
+
 #[derive(Debug, Clone, Serialize)]
 pub struct LogsTail {
+    pub subscription: u64,
     pub display_lines: Vec<DisplayLine>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsRotated {
+    pub subscription: u64,
+    /// Which of the subscription's logsets
+    pub logset: String,
+    pub rotation: Rotation,
+}
+
+/// After this there's nothing more for the subscription, and it's gone
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsDone {
+    pub subscription: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnsubscribeRequest {
+    pub subscription: u64,
+}
+
+/// Something the client should hear about after a read, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TailEvent {
+    Lines(Vec<DisplayLine>),
+    Rotated(Rotation),
+}
+
 #[derive(Debug)]
 pub struct Context {
-    cols: usize,
-    filter: Option<Regex>,
-    file: PathBuf,
-    _watcher: RecommendedWatcher,
-    pos: u64,
+    records: Records,
+    /// Where we are in the logset's feed
+    subscriber: Subscriber,
+    /// Logical line number of the first line read
+    first_lln: usize,
     lines_read: usize,
+    lln_relative: bool,
 }
 
 impl Context {
-    pub fn new(
-        file: PathBuf,
-        cols: usize,
-        filter: Option<Regex>,
+    pub async fn new(
+        feeds: &Feeds,
+        logset: &Logset,
+        wrap: Wrap,
+        filter: LineFilter,
+        backfill: Option<Backfill>,
     ) -> Result<(Self, watch::Receiver<Option<u64>>)> {
-        let len = std::fs::metadata(&file)?.len();
-        let (tx, rx) = watch::channel(None);
-        tx.send_replace(Some(len));
-        let mut watcher = notify::recommended_watcher({
-            let file = file.clone();
-            move |res: Result<notify::Event, notify::Error>| {
-                use notify::event::ModifyKind;
-                match res {
-                    Ok(ev) => match ev.kind {
-                        EventKind::Modify(ModifyKind::Data(_)) => {
-                            debug!("watched file {} data changed", file.display());
-                            if let Ok(meta) = std::fs::metadata(&file) {
-                                tx.send_replace(Some(meta.len()));
-                            }
-                        }
-                        EventKind::Modify(ModifyKind::Name(_)) => {
-                            debug!("watched file {} was renamed", file.display());
-                            tx.send_replace(None);
+        // walking back through the files for a backfill, or bisecting them
+        // for since, can take a while, so keep it off the connection's task
+        let since = filter.since;
+        let (reader, lines_read, lln_relative) = tokio::task::spawn_blocking({
+            let indexes = feeds.indexes().clone();
+            let logset = logset.clone();
+            move || start(&indexes, &logset, backfill, since)
+        })
+        .await??;
+        let (subscriber, rx) = feeds.subscribe(logset, reader)?;
+        let records = Records::new(wrap, filter);
+        let ctx =
+            Self { records, subscriber, first_lln: lines_read, lines_read, lln_relative };
+        Ok((ctx, rx))
+    }
+
+    pub fn lln_relative(&self) -> bool {
+        self.lln_relative
+    }
+
+    /// Rewrap the lines sent so far at a new width, as many as the feed
+    /// still has; lines read from now on are wrapped at it too
+    pub fn resize(&mut self, cols: usize) -> Vec<DisplayLine> {
+        let kept = self.subscriber.kept();
+        let kept = kept.into_iter().map(|(n, parsed)| (self.first_lln + n, parsed));
+        self.records.rewrap(cols, &kept.collect::<Vec<_>>())
+    }
+
+    /// The feed's events since the last read, as lines to send and
+    /// rotations to tell of; how much is read at a time is down to
+    /// `Feed::read`.  Logical line numbers keep counting up across
+    /// rotations.
+    pub async fn read_to(&mut self, len: u64) -> Result<Vec<TailEvent>> {
+        let mut events = vec![];
+        let mut lines = vec![];
+        let mut file = None;
+        for ev in self.subscriber.read(len).await? {
+            match ev {
+                FeedEvent::Line(line) => {
+                    let Line { at: (id, _), parsed, starts_record, .. } = line;
+                    // each file's lines go out separately, as they're read
+                    if file.replace(id).is_some_and(|file| file != id) {
+                        self.records.flush(&mut lines);
+                        if !lines.is_empty() {
+                            events.push(TailEvent::Lines(std::mem::take(&mut lines)));
                         }
-                        EventKind::Remove(_) => {
-                            debug!("watched file {} was removed", file.display());
-                            tx.send_replace(None);
+                    }
+                    self.records.push(self.lines_read, parsed, starts_record, &mut lines);
+                    self.lines_read += 1;
+                }
+                FeedEvent::Rotated(rotation) => {
+                    self.records.flush(&mut lines);
+                    if !lines.is_empty() {
+                        events.push(TailEvent::Lines(std::mem::take(&mut lines)));
+                    }
+                    events.push(TailEvent::Rotated(rotation));
+                }
+            }
+        }
+        // the last record may not be complete, but don't hold it back
+        self.records.flush(&mut lines);
+        if !lines.is_empty() {
+            events.push(TailEvent::Lines(lines));
+        }
+        Ok(events)
+    }
+}
+
+/// A reader for a logset, started at the later of the backfill window and
+/// since, along with how many lines come before there and whether that's
+/// only counting from there
+fn start(
+    indexes: &Arc<Indexes>,
+    logset: &Logset,
+    backfill: Option<Backfill>,
+    since: Option<DateTime<Utc>>,
+) -> Result<(Reader, usize, bool)> {
+    let (mut reader, segments) = Reader::open(indexes, logset)?;
+    let mut start = None;
+    if let Some(backfill) = backfill {
+        start = Some(backfill_start(&segments, backfill)?);
+    }
+    if let Some(since) = since {
+        // skip straight to since rather than reading and dropping
+        // everything before it; with a backfill too, the later wins
+        let index = indexes.logset(logset)?;
+        let cursor = index.seek_time(since, |line| reader.parser().timestamp(line))?;
+        let (path, offset) = index.file_at(cursor);
+        // listed separately, so a rotation in between could mean the
+        // index has a file we don't; then just read from the start
+        if let Some(seg) = reader.paths().position(|p| p == path) {
+            start = start.max(Some((seg, offset)));
+        }
+    }
+    let Some((seg, offset)) = start else {
+        return Ok((reader, 0, false));
+    };
+    reader.seek(seg, offset);
+    // if the line index already covers the start of the window, we can say
+    // exactly which logical line it is
+    Ok(match lines_before(indexes, &segments[..=seg], offset)? {
+        Some(n) => (reader, n, false),
+        None => (reader, 0, true),
+    })
+}
+
+/// Where to start reading from so that only the backfill window is read,
+/// as an index into segments and an offset into that segment.  Segments
+/// are read as one continuous stream, each ending on a line boundary.
+fn backfill_start(
+    segments: &[(std::fs::File, u64)],
+    backfill: Backfill,
+) -> Result<(usize, u64)> {
+    use std::os::unix::fs::FileExt;
+    let mut buf = vec![0u8; 64 * 1024];
+    match backfill {
+        Backfill::Lines(0) => {
+            let last = segments.len() - 1;
+            backfill_start(&segments[last..], Backfill::Bytes(0))
+                .map(|(_, offset)| (last, offset))
+        }
+        Backfill::Lines(n) => {
+            // a newline at the very end terminates the last line, rather
+            // than starting a new one
+            let mut at_end = true;
+            let mut newlines = 0;
+            for (i, (f, len)) in segments.iter().enumerate().rev() {
+                let mut end = *len;
+                while end > 0 {
+                    let start = end.saturating_sub(buf.len() as u64);
+                    let chunk = &mut buf[..(end - start) as usize];
+                    f.read_exact_at(chunk, start)?;
+                    for j in (0..chunk.len()).rev() {
+                        let at_end = std::mem::replace(&mut at_end, false);
+                        if chunk[j] == b'\n' && !at_end {
+                            newlines += 1;
+                            if newlines == n {
+                                return Ok((i, start + j as u64 + 1));
+                            }
                         }
-                        _ => {}
-                    },
-                    Err(e) => {
-                        error!("watch error: {e}");
                     }
+                    end = start;
+                }
+            }
+            Ok((0, 0))
+        }
+        Backfill::Bytes(n) => {
+            let mut remaining = n;
+            for (i, (f, len)) in segments.iter().enumerate().rev() {
+                if remaining > *len {
+                    remaining -= len;
+                    continue;
+                }
+                // move forward to the next line start, or if the window
+                // doesn't contain one, back to the start of the line
+                let mut start = len - remaining;
+                if start == 0 {
+                    return Ok((i, 0));
+                }
+                start -= 1;
+                while start < *len {
+                    let end = (start + buf.len() as u64).min(*len);
+                    let chunk = &mut buf[..(end - start) as usize];
+                    f.read_exact_at(chunk, start)?;
+                    if let Some(j) = chunk.iter().position(|b| *b == b'\n') {
+                        return Ok((i, start + j as u64 + 1));
+                    }
+                    start += chunk.len() as u64;
+                }
+                let mut end = len - remaining;
+                while end > 0 {
+                    let start = end.saturating_sub(buf.len() as u64);
+                    let chunk = &mut buf[..(end - start) as usize];
+                    f.read_exact_at(chunk, start)?;
+                    if let Some(j) = chunk.iter().rposition(|b| *b == b'\n') {
+                        return Ok((i, start + j as u64 + 1));
+                    }
+                    end = start;
                 }
+                return Ok((i, 0));
             }
-        })?;
-        watcher.watch(&file, RecursiveMode::NonRecursive)?;
-        Ok((Self { cols, filter, file, _watcher: watcher, pos: 0, lines_read: 0 }, rx))
+            Ok((0, 0))
+        }
     }
+}
 
-    /// Returns the incremental read
-    pub async fn read_to(&mut self, len: u64) -> Result<Vec<DisplayLine>> {
-        if self.pos >= len {
-            // CR alee: handle non-appends
-            return Ok(vec![]);
+/// How many lines come before offset into the last of segments, if the
+/// line indexes know
+fn lines_before(
+    indexes: &Indexes,
+    segments: &[(std::fs::File, u64)],
+    offset: u64,
+) -> Result<Option<usize>> {
+    let mut n = 0;
+    let (last, segments) = segments.split_last().unwrap();
+    for (f, len) in segments {
+        let index = indexes.get(f)?;
+        let index = index.lock().unwrap();
+        if index.indexed() < *len {
+            return Ok(None);
+        }
+        n += index.lines();
+    }
+    let index = indexes.get(&last.0)?;
+    let line = index.lock().unwrap().line_at(&last.0, offset)?;
+    Ok(line.map(|line| n + line))
+}
+
+/// Read a window of a logset on its own, apart from any tailing.  Reading
+/// by logical line doesn't wait for the line index to get there if it's
+/// still being built; there are just no lines yet.
+async fn range(
+    indexes: &Arc<Indexes>,
+    logset: &Logset,
+    req: &RangeRequest,
+) -> Result<RangeResponse> {
+    let filter = req.display.line_filter()?;
+    let count = req.count.min(MAX_RANGE_LINES);
+    // detecting the format, bisecting and reading all go to the files, so
+    // keep them off the connection's task
+    let (parser, index, lines) = tokio::task::spawn_blocking({
+        let indexes = indexes.clone();
+        let logset = logset.clone();
+        let from = req.from;
+        move || -> Result<_> {
+            let parser = LineParser::for_logset(&logset)?;
+            let index = indexes.logset(&logset)?;
+            let lines = match from {
+                RangeFrom::Lln(n) => index.lines(n, count)?,
+                RangeFrom::Cursor(cursor) => Some(index.lines_at(cursor, count)?),
+                RangeFrom::Time(t) => {
+                    let cursor = index.seek_time(t, |line| parser.timestamp(line))?;
+                    Some(index.lines_at(cursor, count)?)
+                }
+            };
+            Ok((parser, index, lines))
+        }
+    })
+    .await??;
+    let mut display_lines = vec![];
+    let (next, lln_relative) = match lines {
+        Some(lines) => {
+            // records that started before the window are cut short
+            let DisplayOptions { cols, hanging_indent, .. } = req.display;
+            let wrap = Wrap { cols, hanging_indent };
+            let mut records = Records::new(wrap, filter);
+            let base = lines.lln.unwrap_or(0);
+            for (i, line) in lines.lines.iter().enumerate() {
+                let parsed =
+                    parser.parse(line).unwrap_or_else(|_| ParsedLine::plain(line));
+                let starts_record = parser.starts_record(line, &parsed);
+                records.push(base + i, parsed, starts_record, &mut display_lines);
+            }
+            records.flush(&mut display_lines);
+            (lines.next, lines.lln.is_none())
+        }
+        // past the end, or past where the index has got to so far
+        None => (index.len(), index.building()),
+    };
+    Ok(RangeResponse {
+        display_lines,
+        next,
+        lln_relative,
+        len: index.len(),
+        total_lines: index.total_lines(),
+    })
+}
+
+/// A logs or merge request's logsets, each tailed with its own context,
+/// with their lines merged into one stream
+struct Subscription {
+    /// Each logset's context, until it's done, so it isn't holding on to
+    /// its place in the feed
+    sources: Vec<Option<(Context, watch::Receiver<Option<u64>>)>>,
+    names: Vec<String>,
+    merge: Merge,
+}
+
+/// What woke a subscription up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// A source's watch fired, with its new length, or None if it's gone
+    Source(usize, Option<u64>),
+    /// Lines have waited long enough for the other sources
+    Deadline,
+}
+
+/// What a change comes to, to pass on
+#[derive(Debug)]
+enum Update {
+    Tail(Vec<DisplayLine>),
+    Rotated(String, Rotation),
+    Done,
+}
+
+impl Subscription {
+    async fn new(
+        feeds: &Feeds,
+        logsets: &[(String, &Logset)],
+        options: &LogsOptions,
+    ) -> Result<Self> {
+        let filter = LineFilter {
+            since: options.since,
+            until: options.until,
+            min_level: options.min_level,
+            ..options.display.line_filter()?
+        };
+        let names = logsets.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+        let merge = Merge::new(&names);
+        // leaving room for the source labels
+        let cols = options.display.cols.saturating_sub(merge.label_width()).max(1);
+        let wrap = Wrap { cols, hanging_indent: options.display.hanging_indent };
+        let mut sources = vec![];
+        for (_, logset) in logsets {
+            let filter = filter.clone();
+            let source =
+                Context::new(feeds, logset, wrap, filter, options.backfill).await?;
+            sources.push(Some(source));
+        }
+        Ok(Subscription { sources, names, merge })
+    }
+
+    /// If any source's llns count from the start of its backfill window
+    fn lln_relative(&self) -> bool {
+        self.sources.iter().flatten().any(|(ctx, _)| ctx.lln_relative())
+    }
+
+    fn resize(&mut self, cols: usize) -> Vec<DisplayLine> {
+        let cols = cols.saturating_sub(self.merge.label_width()).max(1);
+        let lines = self
+            .sources
+            .iter_mut()
+            .map(|source| source.as_mut().map_or(vec![], |(ctx, _)| ctx.resize(cols)))
+            .collect();
+        self.merge.replace(lines, Instant::now())
+    }
+
+    async fn changed(&mut self) -> Change {
+        let deadline = self.merge.deadline();
+        let changes = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, source)| Some((i, source.as_mut()?)))
+            .map(|(i, (_, rx))| {
+                Box::pin(async move {
+                    // the watcher going away is as good as the file going away
+                    match rx.changed().await {
+                        Ok(()) => Change::Source(i, *rx.borrow_and_update()),
+                        Err(_) => Change::Source(i, None),
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let changed = async move {
+            match changes.is_empty() {
+                true => future::pending().await,
+                false => future::select_all(changes).await.0,
+            }
+        };
+        let deadline = async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+        match future::select(Box::pin(changed), Box::pin(deadline)).await {
+            Either::Left((change, _)) => change,
+            Either::Right(_) => Change::Deadline,
+        }
+    }
+
+    async fn read_to(&mut self, i: usize, len: u64) -> Result<Vec<TailEvent>> {
+        match &mut self.sources[i] {
+            Some((ctx, _)) => ctx.read_to(len).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Nothing more is coming from source i
+    fn done(&mut self, i: usize) {
+        self.sources[i] = None;
+        self.merge.done(i);
+    }
+
+    async fn update(&mut self, change: Change) -> Vec<Update> {
+        let mut updates = vec![];
+        let now = Instant::now();
+        match change {
+            Change::Source(i, Some(len)) => match self.read_to(i, len).await {
+                Ok(events) => {
+                    for ev in events {
+                        match ev {
+                            TailEvent::Lines(lines) => self.merge.push(i, lines, now),
+                            TailEvent::Rotated(rotation) => updates
+                                .push(Update::Rotated(self.names[i].clone(), rotation)),
+                        }
+                    }
+                }
+                // which is the end of the source, not the connection
+                Err(e) => {
+                    error!("while reading {}: {e:#}", self.names[i]);
+                    self.done(i);
+                }
+            },
+            // file closed
+            Change::Source(i, None) => self.done(i),
+            Change::Deadline => {}
+        }
+        let lines = self.merge.drain(now);
+        if !lines.is_empty() {
+            updates.push(Update::Tail(lines));
+        }
+        if self.merge.is_done() {
+            updates.push(Update::Done);
+        }
+        updates
+    }
+}
+
+/// A connection's logs and merge requests, by subscription id, for as long
+/// as they're tailing
+#[derive(Default)]
+struct Subscriptions {
+    next_id: u64,
+    tails: HashMap<u64, Subscription>,
+}
+
+impl Subscriptions {
+    fn add(&mut self, sub: Subscription) -> u64 {
+        self.next_id += 1;
+        self.tails.insert(self.next_id, sub);
+        self.next_id
+    }
+
+    fn get_mut(&mut self, subscription: u64) -> Result<&mut Subscription> {
+        self.tails.get_mut(&subscription).ok_or_else(|| no_subscription(subscription))
+    }
+
+    fn remove(&mut self, subscription: u64) -> Result<Subscription> {
+        self.tails.remove(&subscription).ok_or_else(|| no_subscription(subscription))
+    }
+}
+
+/// The error for a subscription id we don't have
+fn no_subscription(subscription: u64) -> anyhow::Error {
+    json_rpc::Error::new(
+        json_rpc::NO_SUBSCRIPTION,
+        format!("no subscription {subscription}"),
+    )
+    .into()
+}
+
+/// Which subscription changed first, and how
+async fn herald_of_the_change(subs: &mut Subscriptions) -> (u64, Change) {
+    if subs.tails.is_empty() {
+        return future::pending().await;
+    }
+    let changes = subs
+        .tails
+        .iter_mut()
+        .map(|(&id, sub)| Box::pin(async move { (id, sub.changed().await) }));
+    future::select_all(changes).await.0
+}
+
+/// The logset called name, if there is one
+fn logset<'a>(config: &'a Config, name: &str) -> Result<&'a Logset> {
+    config.logsets.get(name).ok_or_else(|| {
+        json_rpc::Error::new(json_rpc::LOGSET_NOT_FOUND, format!("no logset {name}"))
+            .into()
+    })
+}
+
+/// A filter regex, with where it went wrong if it doesn't parse
+fn filter_regex(s: &str) -> Result<Regex> {
+    Regex::new(s).map_err(|e| {
+        let (column, kind) = match regex_syntax::Parser::new().parse(s) {
+            Err(regex_syntax::Error::Parse(e)) => {
+                (e.span().start.column, e.kind().to_string())
+            }
+            Err(regex_syntax::Error::Translate(e)) => {
+                (e.span().start.column, e.kind().to_string())
+            }
+            // e.g. too big, which isn't anywhere in particular
+            _ => return json_rpc::Error::new(json_rpc::BAD_FILTER, e).into(),
+        };
+        json_rpc::Error::new(
+            json_rpc::BAD_FILTER,
+            format!("bad filter regex at column {column}: {kind}"),
+        )
+        .with_data(serde_json::json!({ "column": column }))
+        .into()
+    })
+}
+
+fn query(s: &str) -> Result<Query> {
+    Query::parse(s).map_err(|e| match e.downcast_ref::<QueryError>() {
+        Some(qe) => json_rpc::Error::new(json_rpc::BAD_FILTER, qe)
+            .with_data(serde_json::json!({ "column": qe.column }))
+            .into(),
+        None => e,
+    })
+}
+
+/// The response to a message, one request or a batch of them, if it wants
+/// one; anything that fails gets an error response and the connection
+/// carries on
+async fn respond(
+    config: &Config,
+    feeds: &Feeds,
+    subs: &mut Subscriptions,
+    s: &str,
+) -> Result<Option<String>> {
+    let value = match serde_json::from_str(s) {
+        Ok(value) => value,
+        Err(e) => {
+            let error = json_rpc::Error::new(json_rpc::PARSE_ERROR, e);
+            let response = json_rpc::Response::<()>::error(json_rpc::Id::Null, error);
+            return Ok(Some(serde_json::to_string(&response)?));
+        }
+    };
+    let response = match value {
+        serde_json::Value::Array(batch) if batch.is_empty() => {
+            let error = json_rpc::Error::new(json_rpc::INVALID_REQUEST, "empty batch");
+            Some(serde_json::to_value(json_rpc::Response::<()>::error(
+                json_rpc::Id::Null,
+                error,
+            ))?)
+        }
+        // in order, since e.g. resize depends on logs
+        serde_json::Value::Array(batch) => {
+            let mut responses = vec![];
+            for value in batch {
+                responses.extend(respond_one(config, feeds, subs, value).await?);
+            }
+            (!responses.is_empty()).then_some(serde_json::Value::Array(responses))
+        }
+        value => respond_one(config, feeds, subs, value).await?,
+    };
+    Ok(response.map(|response| response.to_string()))
+}
+
+/// The response to one request, unless it's a notification
+async fn respond_one(
+    config: &Config,
+    feeds: &Feeds,
+    subs: &mut Subscriptions,
+    value: serde_json::Value,
+) -> Result<Option<serde_json::Value>> {
+    let req = match json_rpc::Request::deserialize(&value) {
+        Ok(req) => req,
+        Err(e) => {
+            // answered even without an id, since there's no telling whether
+            // it was meant to be a notification
+            let id = value.get("id").and_then(|id| json_rpc::Id::deserialize(id).ok());
+            let error = json_rpc::Error::new(json_rpc::INVALID_REQUEST, e);
+            let response =
+                json_rpc::Response::<()>::error(id.unwrap_or(json_rpc::Id::Null), error);
+            return Ok(Some(serde_json::to_value(response)?));
+        }
+    };
+    let result = handle_request(config, feeds, subs, &req).await;
+    let Some(id) = req.id else {
+        if let Err(e) = result {
+            debug!("error in notification {}: {e:#}", req.method);
+        }
+        return Ok(None);
+    };
+    let response = match result {
+        Ok(result) => json_rpc::Response::result(id, result),
+        Err(e) => {
+            debug!("error response: {e:#}");
+            json_rpc::Response::error(id, json_rpc::Error::from_anyhow(e))
+        }
+    };
+    Ok(Some(serde_json::to_value(response)?))
+}
+
+async fn handle_request(
+    config: &Config,
+    feeds: &Feeds,
+    subs: &mut Subscriptions,
+    req: &json_rpc::Request,
+) -> Result<serde_json::Value> {
+    match req.method()? {
+        json_rpc::Method::List => {
+            // detecting reads the start of each file, so keep that off the
+            // connection's task
+            let logsets = config.logsets.clone();
+            let mut logsets = tokio::task::spawn_blocking(move || {
+                logsets
+                    .into_iter()
+                    .map(|(name, logset)| match logset.format {
+                        Some(format) => {
+                            LogsetInfo { name, format: Some(format), detected: false }
+                        }
+                        None => LogsetInfo {
+                            name,
+                            format: parser::detect_format(&logset).ok(),
+                            detected: true,
+                        },
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await?;
+            logsets.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(serde_json::to_value(logsets)?)
+        }
+        json_rpc::Method::Logs => {
+            let q: LogsRequest = req.params()?;
+            let logset = logset(config, &q.logset)?;
+            let sub = Subscription::new(feeds, &[(q.logset, logset)], &q.options).await?;
+            let lln_relative = sub.lln_relative();
+            let subscription = subs.add(sub);
+            Ok(serde_json::to_value(LogsResponse { subscription, lln_relative })?)
+        }
+        json_rpc::Method::Merge => {
+            let q: MergeRequest = req.params()?;
+            if q.logsets.is_empty() {
+                return Err(
+                    json_rpc::Error::new(json_rpc::INVALID_PARAMS, "no logsets").into()
+                );
+            }
+            let logsets = q
+                .logsets
+                .iter()
+                .map(|name| Ok((name.clone(), logset(config, name)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let sub = Subscription::new(feeds, &logsets, &q.options).await?;
+            let lln_relative = sub.lln_relative();
+            let subscription = subs.add(sub);
+            Ok(serde_json::to_value(LogsResponse { subscription, lln_relative })?)
+        }
+        json_rpc::Method::Resize => {
+            let q: ResizeRequest = req.params()?;
+            let sub = subs.get_mut(q.subscription)?;
+            Ok(serde_json::to_value(ResizeResponse {
+                display_lines: sub.resize(q.cols),
+            })?)
+        }
+        json_rpc::Method::Range => {
+            let q: RangeRequest = req.params()?;
+            let logset = logset(config, &q.logset)?;
+            Ok(serde_json::to_value(range(feeds.indexes(), logset, &q).await?)?)
+        }
+        json_rpc::Method::Unsubscribe => {
+            let q: UnsubscribeRequest = req.params()?;
+            subs.remove(q.subscription)?;
+            Ok(serde_json::Value::Bool(true))
+        }
+        // only the server sends these
+        json_rpc::Method::Tail | json_rpc::Method::Rotated | json_rpc::Method::Done => {
+            Err(json_rpc::Error::new(json_rpc::METHOD_NOT_FOUND, "not a request").into())
         }
-        let mut lines = vec![];
-        let mut file = File::open(&self.file).await?;
-        file.seek(SeekFrom::Start(self.pos)).await?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).await?;
-        debug!("pre: pos = {}, lines read = {}", self.pos, self.lines_read);
-        // iterate over complete lines only (ending \r\n or \n)
-        for line in contents.split_inclusive("\n") {
-            if !line.ends_with("\n") {
-                break;
-            }
-            self.pos += line.len() as u64;
-            let line = line.trim_end_matches("\n");
-            let line = line.trim_end_matches("\r");
-            if let Ok(Some(p)) = parser::parse_log_line(
-                self.lines_read,
-                self.cols,
-                line,
-                self.filter.as_ref(),
-            ) {
-                lines.extend(p);
-            }
-            self.lines_read += 1;
-        }
-        debug!("post: pos = {}, lines read = {}", self.pos, self.lines_read);
-        Ok(lines)
-    }
-}
-
-async fn herald_of_the_change(
-    ctx: &mut Option<(Context, watch::Receiver<Option<u64>>)>,
-) -> Result<(&mut Context, Option<u64>)> {
-    if let Some((ref mut ctx, rx)) = ctx.as_mut() {
-        rx.changed().await?;
-        let changed = { *rx.borrow_and_update() };
-        Ok((ctx, changed))
-    } else {
-        future::pending().await
     }
 }
 
 async fn handle_ws_message(
     config: &Config,
+    feeds: &Feeds,
     tx: &mut SplitSink<WebSocket, Message>,
-    ctx: &mut Option<(Context, watch::Receiver<Option<u64>>)>,
+    subs: &mut Subscriptions,
     msg: Message,
 ) -> Result<()> {
     if let Ok(s) = msg.to_str() {
         debug!("received: {}", s);
-        let h: json_rpc::RequestHeader = serde_json::from_str(s)?;
-        if h.method == json_rpc::Method::List {
-            let logsets = config.logsets.keys().cloned().collect::<Vec<_>>();
-            tx.send(Message::text(serde_json::to_string(&json_rpc::Response {
-                id: h.id,
-                result: Some(logsets),
-                error: None,
-            })?))
-            .await?;
-        } else if h.method == json_rpc::Method::Logs {
-            let q: json_rpc::Request<LogsRequest> = serde_json::from_str(s)?;
-            let filter = q.params.filter.as_ref().map(|s| Regex::new(s)).transpose()?;
-            let file = config
-                .logsets
-                .get(&q.params.logset)
-                .ok_or_else(|| anyhow!("logset not found"))?;
-            *ctx = Some(Context::new(file.clone(), q.params.cols, filter)?);
-            tx.send(Message::text(serde_json::to_string(&json_rpc::Response {
-                id: q.id,
-                result: Some(()),
-                error: None,
-            })?))
-            .await?;
+        if let Some(response) = respond(config, feeds, subs, s).await? {
+            tx.send(Message::text(response)).await?;
         }
     }
     Ok(())
@@ -165,48 +884,717 @@ async fn handle_ws_message(
 
 async fn handle_changed(
     tx: &mut SplitSink<WebSocket, Message>,
-    ctx: &mut Context,
-    changed: Option<u64>,
+    subs: &mut Subscriptions,
+    subscription: u64,
+    change: Change,
 ) -> Result<()> {
-    match changed {
-        Some(len) => {
-            let inc = ctx.read_to(len).await?;
-            tx.send(Message::text(serde_json::to_string(&json_rpc::Notification {
-                method: json_rpc::Method::Tail,
-                params: LogsTail { display_lines: inc },
-            })?))
-            .await?;
-        }
-        None => {
-            // file closed
-            tx.send(Message::text(serde_json::to_string(&json_rpc::Notification {
-                method: json_rpc::Method::Done,
-                params: (),
-            })?))
-            .await?;
-        }
+    let Ok(sub) = subs.get_mut(subscription) else {
+        return Ok(());
+    };
+    for update in sub.update(change).await {
+        let msg = match update {
+            Update::Tail(display_lines) => {
+                serde_json::to_string(&json_rpc::Notification::new(
+                    json_rpc::Method::Tail,
+                    LogsTail { subscription, display_lines },
+                ))?
+            }
+            Update::Rotated(logset, rotation) => {
+                serde_json::to_string(&json_rpc::Notification::new(
+                    json_rpc::Method::Rotated,
+                    LogsRotated { subscription, logset, rotation },
+                ))?
+            }
+            Update::Done => {
+                subs.tails.remove(&subscription);
+                serde_json::to_string(&json_rpc::Notification::new(
+                    json_rpc::Method::Done,
+                    LogsDone { subscription },
+                ))?
+            }
+        };
+        tx.send(Message::text(msg)).await?;
     }
     Ok(())
 }
 
-pub async fn handle_ws(config: Arc<Config>, ws: WebSocket) -> Result<()> {
+pub async fn handle_ws(
+    config: Arc<Config>,
+    feeds: Arc<Feeds>,
+    ws: WebSocket,
+) -> Result<()> {
     let (mut tx, mut rx) = ws.split();
-    let mut ctx: Option<(Context, watch::Receiver<Option<u64>>)> = None;
+    let mut subs = Subscriptions::default();
     loop {
         select_biased! {
             msg = rx.next().fuse() => {
                 if let Some(msg) = msg {
                     let msg = msg?;
-                    handle_ws_message(&config, &mut tx, &mut ctx, msg).await?;
+                    handle_ws_message(&config, &feeds, &mut tx, &mut subs, msg).await?;
                 } else {
                     break Ok(());
                 }
             }
-            r = herald_of_the_change(&mut ctx).fuse() => {
-                debug!("changed");
-                let (ctx, rx_tail) = r?;
-                handle_changed(&mut tx, ctx, rx_tail).await?;
+            change = herald_of_the_change(&mut subs).fuse() => {
+                let (subscription, change) = change;
+                debug!("changed: {subscription} {change:?}");
+                handle_changed(&mut tx, &mut subs, subscription, change).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::LogsetKind;
+    use crate::parser::SpanLabel;
+    use std::io::Write;
+
+    fn texts(events: &[TailEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|ev| match ev {
+                TailEvent::Lines(lines) => lines
+                    .iter()
+                    .map(|l| l.spans.iter().map(|s| s.text.as_str()).collect::<String>())
+                    .collect::<Vec<_>>()
+                    .join("|"),
+                TailEvent::Rotated(r) => format!("{r:?}"),
+            })
+            .collect()
+    }
+
+    /// Wait for the logset's line index to be built, so llns come out
+    /// absolute
+    async fn indexed(indexes: &Indexes, logset: &Logset) -> Result<()> {
+        let index = indexes.logset(logset)?;
+        while index.building() {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    /// Read a file holding text with a filter, returning the context and
+    /// every line it sent
+    async fn read_filtered(
+        text: &str,
+        filter: LineFilter,
+    ) -> Result<(Context, Vec<DisplayLine>)> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, text)?;
+        let logset = Logset { path, kind: LogsetKind::File, format: None };
+        let feeds = Feeds::default();
+        indexed(feeds.indexes(), &logset).await?;
+        let (mut ctx, _rx) =
+            Context::new(&feeds, &logset, Wrap::cols(80), filter, None).await?;
+        let mut lines = vec![];
+        for ev in ctx.read_to(u64::MAX).await? {
+            if let TailEvent::Lines(l) = ev {
+                lines.extend(l);
+            }
+        }
+        Ok((ctx, lines))
+    }
+
+    #[tokio::test]
+    async fn test_rotation() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "one\ntwo\nthr")?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let (mut ctx, _rx) = Context::new(
+            &Default::default(),
+            &logset,
+            Wrap::cols(80),
+            Default::default(),
+            None,
+        )
+        .await?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["one|two"]);
+        // copytruncate, flushing the partial line from before
+        std::fs::write(&path, "a\n")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["thr", "Truncated", "a"]);
+        // and one that's grown back past where we'd got by the time we look
+        std::fs::write(&path, "aa\n")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["Truncated", "aa"]);
+        // rename-and-recreate, with the old file still being written to
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"b\nc")?;
+        std::fs::rename(&path, dir.path().join("previous"))?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["b"]);
+        std::fs::write(&path, "d\n")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["c", "Replaced", "d"]);
+        // delete-and-recreate
+        std::fs::remove_file(&path)?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), Vec::<String>::new());
+        std::fs::write(&path, "e\n")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["Replaced", "e"]);
+        assert_eq!(ctx.lines_read, 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_s6() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("@400000006a1b2c3e00000000.s"), "b\n")?;
+        std::fs::write(dir.path().join("@400000006a1b2c3d00000000.s"), "a\n")?;
+        std::fs::write(dir.path().join("current"), "c\n")?;
+        let logset =
+            Logset { path: dir.path().to_path_buf(), kind: LogsetKind::S6, format: None };
+        let (mut ctx, _rx) = Context::new(
+            &Default::default(),
+            &logset,
+            Wrap::cols(80),
+            Default::default(),
+            None,
+        )
+        .await?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["a", "b", "c"]);
+        // s6-log rotating current
+        std::fs::rename(
+            dir.path().join("current"),
+            dir.path().join("@400000006a1b2c3f00000000.s"),
+        )?;
+        std::fs::write(dir.path().join("current"), "d\n")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["Replaced", "d"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backfill() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("@400000006a1b2c3d00000000.s"), "a\nbb\n")?;
+        std::fs::write(dir.path().join("current"), "ccc\ndddd\nee")?;
+        let logset =
+            Logset { path: dir.path().to_path_buf(), kind: LogsetKind::S6, format: None };
+        // with the line index built, llns should be absolute
+        let feeds = Feeds::default();
+        indexed(feeds.indexes(), &logset).await?;
+        for (backfill, expected, lln) in [
+            (Backfill::Lines(0), vec![], None),
+            (Backfill::Lines(1), vec![], None),
+            (Backfill::Lines(2), vec!["dddd"], Some(3)),
+            (Backfill::Lines(4), vec!["bb", "ccc|dddd"], Some(1)),
+            (Backfill::Lines(100), vec!["a|bb", "ccc|dddd"], Some(0)),
+            (Backfill::Bytes(1), vec![], None),
+            (Backfill::Bytes(8), vec!["dddd"], Some(3)),
+            (Backfill::Bytes(9), vec!["dddd"], Some(3)),
+            (Backfill::Bytes(13), vec!["ccc|dddd"], Some(2)),
+            (Backfill::Bytes(14), vec!["bb", "ccc|dddd"], Some(1)),
+        ] {
+            let (mut ctx, _rx) = Context::new(
+                &feeds,
+                &logset,
+                Wrap::cols(80),
+                Default::default(),
+                Some(backfill),
+            )
+            .await?;
+            let events = ctx.read_to(u64::MAX).await?;
+            assert_eq!(texts(&events), expected, "{backfill:?}");
+            let first = events.iter().find_map(|ev| match ev {
+                TailEvent::Lines(lines) => lines.first().map(|l| l.lln),
+                _ => None,
+            });
+            assert_eq!(first, lln, "{backfill:?}");
+            assert!(!ctx.lln_relative());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunked_reads() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        let line = format!("{}\n", "x".repeat(99));
+        std::fs::write(&path, line.repeat(25_000))?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let (mut ctx, mut rx) = Context::new(
+            &Default::default(),
+            &logset,
+            Wrap::cols(200),
+            Default::default(),
+            None,
+        )
+        .await?;
+        let len = *rx.borrow_and_update();
+        let mut chunks = 0;
+        let mut total = 0;
+        while rx.has_changed()? || chunks == 0 {
+            rx.borrow_and_update();
+            for ev in ctx.read_to(len.unwrap()).await? {
+                if let TailEvent::Lines(lines) = ev {
+                    total += lines.len();
+                }
+            }
+            chunks += 1;
+        }
+        assert_eq!((chunks, total), (3, 25_000));
+        // partial lines across reads, and invalid utf-8
+        let mut f = std::fs::OpenOptions::new().append(true).open(&path)?;
+        f.write_all(b"ab\xffc")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), Vec::<String>::new());
+        f.write_all(b"d\r\ne")?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["ab\u{fffd}cd"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_time_window() -> Result<()> {
+        let mut text = String::new();
+        for m in 0..20 {
+            text += &format!("[2024-03-01T14:{m:02}:00Z INFO x] at {m}\n  more {m}\n");
+        }
+        let filter = LineFilter {
+            regex: Some(Regex::new("more")?),
+            since: Some("2024-03-01T14:02:00Z".parse()?),
+            until: Some("2024-03-01T14:04:00Z".parse()?),
+            min_level: None,
+            query: None,
+        };
+        let (ctx, lines) = read_filtered(&text, filter).await?;
+        // continuation lines go with the line before, and llns are absolute
+        // even though reading skipped ahead to since
+        let lines = lines.iter().map(|l| (l.lln, l.record));
+        assert_eq!(lines.collect::<Vec<_>>(), vec![(4, 4), (5, 4), (6, 6), (7, 6)]);
+        assert!(!ctx.lln_relative());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_level() -> Result<()> {
+        let text = "before\n\
+                    [2024-03-01T14:00:00Z TRACE x] a\n\
+                    [2024-03-01T14:00:01Z WARN x] b\n  b cont\n\
+                    [2024-03-01T14:00:02Z DEBUG x] c\n  c cont\n\
+                    [2024-03-01T14:00:03Z ERROR x] d\n";
+        let filter = LineFilter {
+            regex: Some(Regex::new("b|d")?),
+            min_level: Some(log::Level::Warn),
+            ..Default::default()
+        };
+        let (_, lines) = read_filtered(text, filter).await?;
+        // llns count the lines that were dropped too
+        let lines = lines.iter().map(|l| (l.lln, l.ll));
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec![(2, Some(1)), (3, Some(1)), (6, Some(0))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query() -> Result<()> {
+        let text = "[2024-03-01T14:00:00Z TRACE x] a cont\n\
+                    [2024-03-01T14:00:01Z WARN x] b\n  b cont\n\
+                    [2024-03-01T14:00:02Z DEBUG x] c\n  c cont\n\
+                    [2024-03-01T14:00:03Z ERROR x] d\n";
+        let query = Query::parse("cont AND (level>=warn OR target=y) AND NOT ping")?;
+        let filter = LineFilter { query: Some(query), ..Default::default() };
+        let (_, lines) = read_filtered(text, filter).await?;
+        // the whole record goes, and only the words looked for light up
+        assert_eq!(lines.iter().map(|l| l.lln).collect::<Vec<_>>(), vec![1, 2]);
+        let matched = lines
+            .iter()
+            .flat_map(|l| &l.spans)
+            .filter(|span| span.label == SpanLabel::TextMatch)
+            .map(|span| span.text.as_str());
+        assert_eq!(matched.collect::<Vec<_>>(), vec!["cont"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_records() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "[2024-03-01T14:00:00Z ERROR x] panicked\n")?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let filter =
+            LineFilter { regex: Some(Regex::new("main.rs")?), ..Default::default() };
+        let (mut ctx, _rx) =
+            Context::new(&Default::default(), &logset, Wrap::cols(80), filter, None)
+                .await?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), Vec::<String>::new());
+        // the rest of the record matches, so all of it goes out
+        let mut f = std::fs::OpenOptions::new().append(true).open(&path)?;
+        f.write_all(b"  at main.rs:3\n")?;
+        let events = ctx.read_to(u64::MAX).await?;
+        assert_eq!(
+            texts(&events),
+            vec!["[2024-03-01T14:00:00Z ERROR x] panicked|  at main.rs:3"]
+        );
+        let TailEvent::Lines(lines) = &events[0] else { panic!() };
+        let lines = lines.iter().map(|l| (l.lln, l.record, l.ll));
+        assert_eq!(lines.collect::<Vec<_>>(), vec![(0, 0, Some(0)), (1, 0, Some(0))]);
+        // and then so does anything else that turns up for it
+        f.write_all(
+            b"  at lib.rs:1\n\
+              [2024-03-01T14:00:01Z INFO x] ok\n",
+        )?;
+        assert_eq!(texts(&ctx.read_to(u64::MAX).await?), vec!["  at lib.rs:1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resize() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        let text = "[2024-03-01T14:00:00Z INFO x] one two three\n  four\n\
+                    [2024-03-01T14:00:01Z INFO x] five\n\
+                    [2024-03-01T14:00:02Z INFO x] go\n";
+        std::fs::write(&path, text)?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let filter =
+            || LineFilter { regex: Some(Regex::new("o").unwrap()), ..Default::default() };
+        let (mut ctx, _rx) =
+            Context::new(&Default::default(), &logset, Wrap::cols(80), filter(), None)
+                .await?;
+        let lines = ctx.read_to(u64::MAX).await?;
+        let TailEvent::Lines(sent) = &lines[0] else { panic!() };
+        assert_eq!(sent.iter().map(|l| l.record).collect::<Vec<_>>(), vec![0, 0, 3]);
+        // the file's gone, so this can't be reading it again
+        std::fs::remove_file(&path)?;
+        let narrow = ctx.resize(12);
+        std::fs::write(&path, text)?;
+        let (mut fresh, _rx) =
+            Context::new(&Default::default(), &logset, Wrap::cols(12), filter(), None)
+                .await?;
+        assert_eq!(vec![TailEvent::Lines(narrow)], fresh.read_to(u64::MAX).await?);
+        assert_eq!(vec![TailEvent::Lines(ctx.resize(80))], lines);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_feed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "one\ntwo\nthree\n")?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let feeds = Feeds::default();
+        indexed(feeds.indexes(), &logset).await?;
+        let (mut all, _rx) =
+            Context::new(&feeds, &logset, Wrap::cols(80), Default::default(), None)
+                .await?;
+        assert_eq!(texts(&all.read_to(u64::MAX).await?), vec!["one|two|three"]);
+        // sharing the feed, but with a filter and wrapping of its own, and
+        // starting from lines the feed has read already
+        let filter = LineFilter { regex: Some(Regex::new("o")?), ..Default::default() };
+        let (mut some, _rx) = Context::new(
+            &feeds,
+            &logset,
+            Wrap::cols(2),
+            filter,
+            Some(Backfill::Lines(2)),
+        )
+        .await?;
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"four\n")?;
+        assert_eq!(texts(&all.read_to(u64::MAX).await?), vec!["four"]);
+        let events = some.read_to(u64::MAX).await?;
+        assert_eq!(texts(&events), vec!["tw|o|fo|ur"]);
+        let TailEvent::Lines(lines) = &events[0] else { panic!() };
+        assert_eq!(lines.iter().map(|l| l.lln).collect::<Vec<_>>(), vec![1, 1, 3, 3]);
+        assert!(!some.lln_relative());
+        // rewrapping is from the feed's lines too
+        let lines = some.resize(80);
+        assert_eq!(texts(&[TailEvent::Lines(lines.clone())]), vec!["two|four"]);
+        assert_eq!(lines.iter().map(|l| l.lln).collect::<Vec<_>>(), vec![1, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "a\nbb\nccc\ndddd\n")?;
+        let logset = Logset { path, kind: LogsetKind::File, format: None };
+        let indexes = Arc::new(Indexes::default());
+        indexed(&indexes, &logset).await?;
+        let mut req = RangeRequest {
+            display: DisplayOptions {
+                cols: 80,
+                hanging_indent: false,
+                filter: None,
+                query: None,
+            },
+            logset: "test".to_string(),
+            from: RangeFrom::Lln(1),
+            count: 2,
+        };
+        let res = range(&indexes, &logset, &req).await?;
+        let lines = res.display_lines.iter().map(|l| (l.lln, l.spans[0].text.as_str()));
+        assert_eq!(lines.collect::<Vec<_>>(), vec![(1, "bb"), (2, "ccc")]);
+        assert_eq!((res.next, res.len, res.total_lines), (9, 14, Some(4)));
+        req.from = RangeFrom::Cursor(res.next);
+        req.display.filter = Some("d+".to_string());
+        let res = range(&indexes, &logset, &req).await?;
+        assert_eq!(res.display_lines.len(), 1);
+        assert_eq!((res.display_lines[0].lln, res.next), (3, 14));
+        // past the end of an index that's all there
+        req.from = RangeFrom::Lln(10);
+        let res = range(&indexes, &logset, &req).await?;
+        assert!(res.display_lines.is_empty() && !res.lln_relative);
+        Ok(())
+    }
+
+    /// The response to s, or null if there isn't one
+    async fn call(
+        config: &Config,
+        feeds: &Feeds,
+        subs: &mut Subscriptions,
+        s: &str,
+    ) -> Result<serde_json::Value> {
+        match respond(config, feeds, subs, s).await? {
+            Some(response) => Ok(serde_json::from_str(&response)?),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "a\n")?;
+        let logset = Logset { path, kind: LogsetKind::File, format: None };
+        let config = Config { logsets: [("test".to_string(), logset)].into() };
+        let feeds = Feeds::default();
+        let mut subs = Subscriptions::default();
+        let logs = |params: &str| {
+            format!(r#"{{"id":4,"method":"logs","params":{{"cols":80,{params}}}}}"#)
+        };
+        let cases = [
+            (r#"{"id":1,"#.to_string(), None, json_rpc::PARSE_ERROR, None),
+            (
+                r#"{"id":2,"method":"frob"}"#.to_string(),
+                Some(2),
+                json_rpc::METHOD_NOT_FOUND,
+                None,
+            ),
+            (
+                r#"{"id":5,"jsonrpc":"1.0","method":"list"}"#.to_string(),
+                Some(5),
+                json_rpc::INVALID_REQUEST,
+                None,
+            ),
+            (
+                r#"{"id":3,"method":"resize","params":{}}"#.to_string(),
+                Some(3),
+                json_rpc::INVALID_PARAMS,
+                None,
+            ),
+            (
+                r#"{"id":3,"method":"resize","params":{"subscription":1,"cols":80}}"#
+                    .to_string(),
+                Some(3),
+                json_rpc::NO_SUBSCRIPTION,
+                None,
+            ),
+            (logs(r#""logset":"nope""#), Some(4), json_rpc::LOGSET_NOT_FOUND, None),
+            (
+                logs(r#""logset":"test","filter":"a(b""#),
+                Some(4),
+                json_rpc::BAD_FILTER,
+                Some(2),
+            ),
+            (
+                logs(r#""logset":"test","query":"a AND (b""#),
+                Some(4),
+                json_rpc::BAD_FILTER,
+                Some(9),
+            ),
+        ];
+        for (request, id, code, column) in cases {
+            let response = call(&config, &feeds, &mut subs, &request).await?;
+            assert_eq!(response["id"].as_u64(), id, "{request}");
+            assert_eq!(response["error"]["code"], code, "{request}");
+            assert_eq!(response["error"]["data"]["column"].as_u64(), column, "{request}");
+        }
+        // and after all that the connection still works
+        let response =
+            call(&config, &feeds, &mut subs, &logs(r#""logset":"test""#)).await?;
+        assert_eq!(response["result"]["lln_relative"], false);
+        assert_eq!(response["result"]["subscription"], 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc() -> Result<()> {
+        let config = Config { logsets: Default::default() };
+        let feeds = Feeds::default();
+        let mut subs = Subscriptions::default();
+        macro_rules! call {
+            ($s:expr) => {
+                call(&config, &feeds, &mut subs, $s).await?
+            };
+        }
+        // any kind of id, and either result or error
+        let response = call!(r#"{"jsonrpc":"2.0","id":"a","method":"list"}"#);
+        assert_eq!(
+            response,
+            serde_json::json!({ "jsonrpc": "2.0", "id": "a", "result": [] })
+        );
+        let response = call!(r#"{"jsonrpc":"2.0","id":null,"method":"list"}"#);
+        assert_eq!(response["id"], serde_json::Value::Null);
+        assert_eq!(response["result"], serde_json::json!([]));
+        let response = call!(r#"{"jsonrpc":"2.0","id":1.5,"method":"frob"}"#);
+        assert_eq!(response["id"], 1.5);
+        assert!(response.get("result").is_none() && response.get("error").is_some());
+        // notifications get nothing, even when they fail
+        assert_eq!(call!(r#"{"method":"list"}"#), serde_json::Value::Null);
+        assert_eq!(call!(r#"{"method":"frob"}"#), serde_json::Value::Null);
+        // batches get a response for each request that isn't a notification
+        let response = call!(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"list"},{"method":"list"},
+                {"id":"x","method":"frob"},1]"#
+        );
+        let responses = response.as_array().unwrap();
+        let ids = responses.iter().map(|r| &r["id"]).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [&serde_json::json!(1), &serde_json::json!("x"), &serde_json::Value::Null]
+        );
+        let codes = responses.iter().map(|r| r["error"]["code"].as_i64());
+        assert_eq!(
+            codes.collect::<Vec<_>>(),
+            [
+                None,
+                Some(json_rpc::METHOD_NOT_FOUND as i64),
+                Some(json_rpc::INVALID_REQUEST as i64)
+            ]
+        );
+        assert_eq!(call!(r#"[{"method":"list"}]"#), serde_json::Value::Null);
+        let response = call!("[]");
+        assert_eq!(response["error"]["code"], json_rpc::INVALID_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logsets = HashMap::new();
+        for name in ["a", "b"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, format!("{name}\n"))?;
+            logsets.insert(
+                name.to_string(),
+                Logset { path, kind: LogsetKind::File, format: None },
+            );
+        }
+        let config = Config { logsets };
+        let feeds = Feeds::default();
+        let mut subs = Subscriptions::default();
+        macro_rules! call {
+            ($method:expr, $params:expr) => {{
+                let request = serde_json::json!({ "id": 1, "method": $method, "params": $params });
+                call(&config, &feeds, &mut subs, &request.to_string()).await?
+            }};
+        }
+        // each logs request is its own subscription, and they all tail at once
+        for (name, subscription) in [("a", 1), ("b", 2), ("a", 3)] {
+            let response =
+                call!("logs", serde_json::json!({ "logset": name, "cols": 80 }));
+            assert_eq!(response["result"]["subscription"], subscription);
+        }
+        assert_eq!(subs.tails.len(), 3);
+        let response = call!("unsubscribe", serde_json::json!({ "subscription": 1 }));
+        assert_eq!(response["result"], true);
+        let mut ids = subs.tails.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![2, 3]);
+        // the rest carry on
+        for (subscription, text) in [(2, "b"), (3, "a")] {
+            let sub = subs.get_mut(subscription)?;
+            let change = sub.changed().await;
+            let updates = sub.update(change).await;
+            let [Update::Tail(lines)] = &updates[..] else { panic!("{updates:?}") };
+            assert_eq!(lines[0].spans[0].text, text);
+        }
+        // and the one that's gone is gone
+        for method in ["unsubscribe", "resize"] {
+            let response =
+                call!(method, serde_json::json!({ "subscription": 1, "cols": 80 }));
+            assert_eq!(response["error"]["code"], json_rpc::NO_SUBSCRIPTION);
+        }
+        let response =
+            call!("resize", serde_json::json!({ "subscription": 2, "cols": 80 }));
+        assert_eq!(response["result"]["display_lines"][0]["spans"][0]["text"], "b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logsets = HashMap::new();
+        for (name, lines) in [
+            (
+                "gw",
+                "[2024-03-01T14:00:01Z INFO gw] a\n[2024-03-01T14:00:04Z INFO gw] c\n",
+            ),
+            (
+                "risk",
+                "[2024-03-01T14:00:02Z WARN risk] b\n  b cont\n\
+                 [2024-03-01T14:00:05Z INFO risk] d\n",
+            ),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, lines)?;
+            logsets.insert(
+                name.to_string(),
+                Logset { path, kind: LogsetKind::File, format: None },
+            );
+        }
+        let config = Config { logsets };
+        let feeds = Feeds::default();
+        let mut subs = Subscriptions::default();
+        let request = serde_json::json!({
+            "id": 1,
+            "method": "merge",
+            "params": { "logsets": ["gw", "risk"], "cols": 40 },
+        });
+        let response = call(&config, &feeds, &mut subs, &request.to_string()).await?;
+        let sub = subs.get_mut(response["result"]["subscription"].as_u64().unwrap())?;
+        let sources = |lines: &[DisplayLine]| {
+            lines
+                .iter()
+                .map(|l| {
+                    assert_eq!(l.spans[0].label, SpanLabel::Source);
+                    (l.spans[0].text.clone(), l.lln)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut changes = vec![];
+        let mut tail = vec![];
+        while changes.len() < 3 {
+            let change = sub.changed().await;
+            changes.push(change);
+            for update in sub.update(change).await {
+                let Update::Tail(lines) = update else { panic!("{update:?}") };
+                tail.push((change, sources(&lines)));
             }
         }
+        // both are read, then d waits for gw to have something later, which
+        // it never does
+        assert!(matches!(
+            changes[..],
+            [Change::Source(..), Change::Source(..), Change::Deadline]
+        ));
+        let gw = |lln| ("gw".to_string(), lln);
+        let risk = |lln| ("risk".to_string(), lln);
+        assert_eq!(
+            tail,
+            vec![
+                (changes[1], vec![gw(0), risk(0), risk(1), gw(1)]),
+                (changes[2], vec![risk(2)])
+            ]
+        );
+        // and the labels leave room for the rest in the cols asked for
+        let lines = sub.resize(30);
+        let mut rows = sources(&lines);
+        rows.dedup();
+        assert_eq!(rows, vec![gw(0), risk(0), risk(1), gw(1), risk(2)]);
+        assert_eq!(lines[0].spans[1].text, "   ");
+        let width = |l: &DisplayLine| l.spans.iter().map(|s| s.text.len()).sum::<usize>();
+        assert!(lines.len() > 5 && lines.iter().all(|l| width(l) <= 30));
+        Ok(())
     }
 }
@@ -0,0 +1,897 @@
+//! One watcher and one reader per logset, however many are tailing it.
+//!
+//! A feed reads and parses each new line of its logset once, and queues the
+//! parsed lines for its subscribers to take at their own pace, each then
+//! filtering and wrapping them its own way.  Feeds are kept by the logset's
+//! current file for as long as someone's subscribed.
+//!
+//! The last KEEP_EVENTS are kept, whether or not everyone's taken them, for
+//! subscribers to wrap again at a new width, and so that someone joining
+//! with a short backfill can start from those.  Anyone starting further back
+//! reads up to where the feed is on their own first, as does anyone who
+//! falls further behind than that.
+
+use crate::{
+    config::{Logset, LogsetKind},
+    index::{file_id, FileId, Indexes, LineIndex},
+    parser::{LineParser, ParsedLine},
+};
+use anyhow::{bail, Result};
+use log::{debug, error};
+use notify::{event::EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+    sync::watch,
+};
+
+/// Most bytes to read per wakeup
+const READ_BUDGET: u64 = 1 << 20;
+/// Lines longer than this are broken up rather than buffered indefinitely
+const MAX_LINE_LEN: usize = 1 << 20;
+/// How much of the start of a file is kept to tell if it's been truncated
+const HEAD_LEN: usize = 256;
+/// Most events a feed keeps, for rewrapping, latecomers and subscribers
+/// that haven't taken them yet
+const KEEP_EVENTS: usize = 100_000;
+
+/// Where in a logset a line starts: the file and the offset into it
+pub type Position = (FileId, u64);
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    /// How many lines the reader read before it
+    pub n: usize,
+    pub at: Position,
+    pub parsed: ParsedLine,
+    pub starts_record: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    /// The file was truncated in place (e.g. logrotate copytruncate)
+    Truncated,
+    /// The file was renamed or removed and a new one created in its place
+    Replaced,
+}
+
+/// What a feed's read comes to, in order
+#[derive(Debug, Clone)]
+pub enum FeedEvent {
+    Line(Line),
+    Rotated(Rotation),
+}
+
+/// Reads a logset's lines in order from some starting point, through any
+/// archives and on into the current file, noticing if it's rotated
+#[derive(Debug)]
+pub struct Reader {
+    parser: LineParser,
+    kind: LogsetKind,
+    file: PathBuf,
+    /// Files that logically precede `file` and are read through first,
+    /// with the offset to start reading each from
+    archives: VecDeque<(PathBuf, FileId, u64)>,
+    handle: File,
+    id: FileId,
+    indexes: Arc<Indexes>,
+    /// Index of the file behind handle, kept up to date as we read it
+    index: Arc<Mutex<LineIndex>>,
+    /// Indexes of the logset's other files, held on to so that they live
+    /// as long as someone's looking at the logset
+    other_indexes: Vec<Arc<Mutex<LineIndex>>>,
+    /// Bytes read from handle so far, including partial
+    pos: u64,
+    /// The first HEAD_LEN bytes of handle, as far as they went when last
+    /// looked at
+    head: Vec<u8>,
+    /// A trailing line that hasn't seen its newline yet
+    partial: Vec<u8>,
+    lines_read: usize,
+    /// Where the next line starts
+    at: Position,
+    /// Where to stop, when catching up to a feed
+    stop: Option<Position>,
+}
+
+impl Reader {
+    /// A reader from the very start of the logset, along with its files as
+    /// they are now and their lengths, to pick somewhere else to start from
+    pub fn open(
+        indexes: &Arc<Indexes>,
+        logset: &Logset,
+    ) -> Result<(Self, Vec<(std::fs::File, u64)>)> {
+        let parser = LineParser::for_logset(logset)?;
+        let file = logset.current();
+        let handle = std::fs::File::open(&file)?;
+        let meta = handle.metadata()?;
+        let index = indexes.get(&handle)?;
+        // listed after opening current, so that a rotation in between
+        // shows up as an archive we already hold open, and is skipped
+        let mut archives = VecDeque::new();
+        let mut other_indexes = vec![];
+        let mut segments = vec![];
+        for archive in logset.archives()? {
+            let f = std::fs::File::open(&archive)?;
+            let archive_meta = f.metadata()?;
+            if file_id(&archive_meta) != file_id(&meta) {
+                other_indexes.push(indexes.get(&f)?);
+                archives.push_back((archive, file_id(&archive_meta), 0));
+                segments.push((f, archive_meta.len()));
+            }
+        }
+        segments.push((handle.try_clone()?, meta.len()));
+        let mut head = vec![0; HEAD_LEN];
+        let n = std::os::unix::fs::FileExt::read_at(&handle, &mut head, 0)?;
+        head.truncate(n);
+        let id = file_id(&meta);
+        let at = archives.front().map_or((id, 0), |(_, id, _)| (*id, 0));
+        let reader = Reader {
+            parser,
+            kind: logset.kind,
+            file,
+            archives,
+            handle: File::from_std(handle),
+            id,
+            indexes: indexes.clone(),
+            index,
+            other_indexes,
+            pos: 0,
+            head,
+            partial: vec![],
+            lines_read: 0,
+            at,
+            stop: None,
+        };
+        Ok((reader, segments))
+    }
+
+    pub fn parser(&self) -> &LineParser {
+        &self.parser
+    }
+
+    /// The files still to be read, in order
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.archives
+            .iter()
+            .map(|(archive, _, _)| archive.as_path())
+            .chain([self.file.as_path()])
+    }
+
+    /// Start from offset into the seg'th of the files still to be read
+    pub fn seek(&mut self, seg: usize, offset: u64) {
+        self.archives.drain(..seg);
+        match self.archives.front_mut() {
+            Some((_, id, start)) => {
+                *start = offset;
+                self.at = (*id, offset);
+            }
+            None => {
+                self.pos = offset;
+                self.at = (self.id, offset);
+            }
+        }
+    }
+
+    /// Read on up to len, noticing if the file was rotated out from under
+    /// us since the last read, returning what was read and whether len (or
+    /// where to stop) was reached.  At most READ_BUDGET bytes are read per
+    /// call.
+    pub async fn read_to(&mut self, len: u64) -> Result<(Vec<FeedEvent>, bool)> {
+        let mut events = vec![];
+        let mut budget = READ_BUDGET;
+        while let Some((archive, id, start)) = self.archives.front().cloned() {
+            let mut f = File::open(&archive).await?;
+            let end = self.limit(id, u64::MAX);
+            let (buf, done) = read_chunk(&mut f, start, end, &mut budget).await?;
+            self.archives[0].2 += buf.len() as u64;
+            events.extend(self.push_bytes(id, start, &buf, done));
+            if self.stopped() {
+                return Ok((events, true));
+            }
+            if !done {
+                return Ok((events, false));
+            }
+            self.archives.pop_front();
+            self.at = match self.archives.front() {
+                Some((_, id, start)) => (*id, *start),
+                None => (self.id, self.pos),
+            };
+        }
+        let meta = std::fs::metadata(&self.file);
+        // a feed can outlive the mtime its parser started out with
+        if let Ok(Ok(mtime)) = meta.as_ref().map(std::fs::Metadata::modified) {
+            self.parser.set_reference(mtime.into());
+        }
+        let regrown = match &meta {
+            Ok(meta) if file_id(meta) == self.id && meta.len() >= self.pos => {
+                self.regrown().await?
+            }
+            _ => false,
+        };
+        match meta {
+            Ok(meta) if file_id(&meta) != self.id => {
+                // renamed-and-recreated or deleted-and-recreated; drain the
+                // rest of the old file before switching over to the new one
+                let end = self.limit(self.id, u64::MAX);
+                let (lines, done) = self.read_lines(end, true, &mut budget).await?;
+                events.extend(lines);
+                if self.stopped() {
+                    return Ok((events, true));
+                }
+                if !done {
+                    return Ok((events, false));
+                }
+                debug!("watched file {} was replaced", self.file.display());
+                let handle = std::fs::File::open(&self.file)?;
+                let index =
+                    std::mem::replace(&mut self.index, self.indexes.get(&handle)?);
+                if self.kind == LogsetKind::S6 {
+                    // the old current is an archive now
+                    self.other_indexes.push(index);
+                }
+                self.handle = File::from_std(handle);
+                self.id = file_id(&meta);
+                self.pos = 0;
+                self.head = self.read_head().await?;
+                self.at = (self.id, 0);
+                events.push(FeedEvent::Rotated(Rotation::Replaced));
+            }
+            // a copytruncate may have grown back past pos before we got to
+            // look, which the start of the file changing gives away
+            Ok(meta) if meta.len() < self.pos || regrown => {
+                debug!("watched file {} was truncated", self.file.display());
+                events.extend(self.push_bytes(self.id, self.pos, &[], true));
+                self.index = self.indexes.reset(&std::fs::File::open(&self.file)?)?;
+                self.pos = 0;
+                self.head = self.read_head().await?;
+                self.at = (self.id, 0);
+                events.push(FeedEvent::Rotated(Rotation::Truncated));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // renamed or removed and not yet recreated; the old file may
+                // still be written to for a bit, so only take complete lines
+                let end = self.limit(self.id, u64::MAX);
+                let (lines, done) = self.read_lines(end, false, &mut budget).await?;
+                events.extend(lines);
+                return Ok((events, done));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let end = self.limit(self.id, len);
+        let (lines, done) = self.read_lines(end, false, &mut budget).await?;
+        events.extend(lines);
+        Ok((events, done))
+    }
+
+    /// Whether the file doesn't start the way it did, and so must have been
+    /// truncated and written again since we last looked
+    async fn regrown(&mut self) -> Result<bool> {
+        let head = self.read_head().await?;
+        let n = head.len().min(self.head.len());
+        if head[..n] != self.head[..n] {
+            return Ok(true);
+        }
+        if head.len() > self.head.len() {
+            self.head = head;
+        }
+        Ok(false)
+    }
+
+    async fn read_head(&mut self) -> Result<Vec<u8>> {
+        let mut budget = HEAD_LEN as u64;
+        let (head, _) =
+            read_chunk(&mut self.handle, 0, HEAD_LEN as u64, &mut budget).await?;
+        Ok(head)
+    }
+
+    /// len, or where to stop if that's sooner and in file id
+    fn limit(&self, id: FileId, len: u64) -> u64 {
+        match self.stop {
+            Some((stop_id, offset)) if stop_id == id => len.min(offset),
+            _ => len,
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop == Some(self.at)
+    }
+
+    /// Whether file id is still to be read, or being read
+    fn has_file(&self, id: FileId) -> bool {
+        self.id == id || self.archives.iter().any(|(_, archive, _)| *archive == id)
+    }
+
+    /// Start from at, if its file is one still to be read
+    fn seek_to(&mut self, (id, offset): Position) -> bool {
+        let seg = match self.archives.iter().position(|(_, archive, _)| *archive == id) {
+            Some(seg) => seg,
+            None if self.id == id => self.archives.len(),
+            None => return false,
+        };
+        self.seek(seg, offset);
+        true
+    }
+
+    /// Read lines from the current handle, from pos up to len, returning
+    /// whether len (or EOF) was reached
+    async fn read_lines(
+        &mut self,
+        len: u64,
+        last: bool,
+        budget: &mut u64,
+    ) -> Result<(Vec<FeedEvent>, bool)> {
+        debug!("pre: pos = {}", self.pos);
+        let (buf, done) = read_chunk(&mut self.handle, self.pos, len, budget).await?;
+        self.index.lock().unwrap().extend(self.pos, &buf);
+        let lines = self.push_bytes(self.id, self.pos, &buf, last && done);
+        self.pos += buf.len() as u64;
+        debug!("post: pos = {}", self.pos);
+        Ok((lines, done))
+    }
+
+    /// Parse the complete lines out of whatever was left over from the last
+    /// read plus bytes, found at offset into file id, keeping any trailing
+    /// partial line for next time.  If last, also take the trailing partial
+    /// line.
+    fn push_bytes(
+        &mut self,
+        id: FileId,
+        offset: u64,
+        bytes: &[u8],
+        last: bool,
+    ) -> Vec<FeedEvent> {
+        let mut partial = std::mem::take(&mut self.partial);
+        let mut at = offset - partial.len() as u64;
+        partial.extend_from_slice(bytes);
+        let mut lines = vec![];
+        let mut start = 0;
+        // iterate over complete lines only (ending \r\n or \n)
+        while let Some(i) = partial[start..].iter().position(|b| *b == b'\n') {
+            lines.push(self.parse_line((id, at), &partial[start..start + i]));
+            start += i + 1;
+            at += i as u64 + 1;
+        }
+        partial.drain(..start);
+        // don't let a runaway line without newlines eat all our memory
+        if (last && !partial.is_empty()) || partial.len() > MAX_LINE_LEN {
+            lines.push(self.parse_line((id, at), &partial));
+            at += partial.len() as u64;
+            partial.clear();
+        }
+        self.partial = partial;
+        self.at = (id, at);
+        lines
+    }
+
+    fn parse_line(&mut self, at: Position, line: &[u8]) -> FeedEvent {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = String::from_utf8_lossy(line);
+        let parsed =
+            self.parser.parse(&line).unwrap_or_else(|_| ParsedLine::plain(&line));
+        let starts_record = self.parser.starts_record(&line, &parsed);
+        let n = self.lines_read;
+        self.lines_read += 1;
+        FeedEvent::Line(Line { n, at, parsed, starts_record })
+    }
+}
+
+/// Read from pos up to len, or as much of that as budget allows, returning
+/// the bytes and whether len (or EOF) was reached
+async fn read_chunk(
+    f: &mut File,
+    pos: u64,
+    len: u64,
+    budget: &mut u64,
+) -> Result<(Vec<u8>, bool)> {
+    let want = len.saturating_sub(pos).min(*budget);
+    let mut buf = vec![];
+    if want > 0 {
+        f.seek(SeekFrom::Start(pos)).await?;
+        f.take(want).read_to_end(&mut buf).await?;
+    }
+    *budget -= buf.len() as u64;
+    let done = (buf.len() as u64) < want || pos + buf.len() as u64 >= len;
+    Ok((buf, done))
+}
+
+/// A feed's events, numbered in order, for its subscribers to take
+#[derive(Debug)]
+struct Events {
+    /// Number of the first event in queue
+    first: u64,
+    queue: VecDeque<FeedEvent>,
+    /// Where the next line read will start
+    end: Position,
+    /// Number of the next event each subscriber has to take
+    next: HashMap<u64, u64>,
+    /// Where the next line starts for each subscriber whose next event was
+    /// dropped before they took it
+    behind: HashMap<u64, Position>,
+    next_subscriber: u64,
+}
+
+impl Events {
+    /// The events since the subscriber last took them, or None if they've
+    /// fallen behind what's kept
+    fn take(&mut self, subscriber: u64) -> Option<Vec<FeedEvent>> {
+        let next = *self.next.get(&subscriber)?;
+        let events = self.queue.range((next - self.first) as usize..).cloned().collect();
+        self.next.insert(subscriber, self.first + self.queue.len() as u64);
+        Some(events)
+    }
+
+    /// Drop all but the last KEEP_EVENTS, however far behind that leaves
+    /// anyone
+    fn trim(&mut self) {
+        while self.queue.len() > KEEP_EVENTS {
+            let ev = self.queue.pop_front().unwrap();
+            let first = self.first;
+            self.first += 1;
+            let waiting = self.next.iter_mut().filter(|(_, next)| **next == first);
+            match ev {
+                // they'll pick up from this line on their own
+                FeedEvent::Line(line) => {
+                    let ids = waiting.map(|(id, _)| *id).collect::<Vec<_>>();
+                    for id in ids {
+                        self.next.remove(&id);
+                        self.behind.insert(id, line.at);
+                    }
+                }
+                // which reading on their own won't tell them of, but
+                // they'll still be reading the right file
+                FeedEvent::Rotated(_) => waiting.for_each(|(_, next)| *next += 1),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Feed {
+    logset: Logset,
+    indexes: Arc<Indexes>,
+    _watcher: RecommendedWatcher,
+    tx: Arc<watch::Sender<Option<u64>>>,
+    reader: tokio::sync::Mutex<Reader>,
+    events: Mutex<Events>,
+}
+
+impl Feed {
+    /// Start a feed from wherever reader is
+    fn new(logset: &Logset, reader: Reader) -> Result<Self> {
+        let file = reader.file.clone();
+        let (tx, _) = watch::channel(Some(std::fs::metadata(&file)?.len()));
+        let tx = Arc::new(tx);
+        // watch the parent directory rather than the file itself, so that
+        // we keep seeing events after the file is renamed away or recreated
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut watcher = notify::recommended_watcher({
+            let dir = dir.clone();
+            let tx = tx.clone();
+            move |res: Result<notify::Event, notify::Error>| match res {
+                Ok(ev) => {
+                    if matches!(ev.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    if matches!(ev.kind, EventKind::Remove(_))
+                        && ev.paths.iter().any(|p| p.ends_with(&dir))
+                    {
+                        debug!("watched directory {} was removed", dir.display());
+                        tx.send_replace(None);
+                        return;
+                    }
+                    if ev.paths.iter().any(|p| p.file_name() == file.file_name()) {
+                        debug!("watched file {} changed: {:?}", file.display(), ev.kind);
+                        // if the file is gone, still wake up the reader so
+                        // it can drain whatever was left in the old file
+                        let len = std::fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+                        tx.send_replace(Some(len));
+                    }
+                }
+                Err(e) => {
+                    error!("watch error: {e}");
+                }
+            }
+        })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        let events = Events {
+            first: 0,
+            queue: VecDeque::new(),
+            end: reader.at,
+            next: HashMap::new(),
+            behind: HashMap::new(),
+            next_subscriber: 0,
+        };
+        Ok(Feed {
+            logset: logset.clone(),
+            indexes: reader.indexes.clone(),
+            _watcher: watcher,
+            tx,
+            reader: tokio::sync::Mutex::new(reader),
+            events: Mutex::new(events),
+        })
+    }
+
+    /// Whether the logset is still there to be fed from
+    fn live(&self) -> bool {
+        self.tx.borrow().is_some()
+    }
+
+    /// Subscribe from wherever reader is, or if there's no reader, from
+    /// wherever the feed is
+    fn subscribe(
+        self: &Arc<Self>,
+        reader: Option<Reader>,
+    ) -> (Subscriber, watch::Receiver<Option<u64>>) {
+        let mut events = self.events.lock().unwrap();
+        let start = reader.as_ref().map_or(events.end, |reader| reader.at);
+        let end = events.first + events.queue.len() as u64;
+        let found = events
+            .queue
+            .iter()
+            .rposition(|ev| matches!(ev, FeedEvent::Line(line) if line.at == start));
+        let (next, stop, skip_to) = match found {
+            // the feed has it already
+            Some(i) => (events.first + i as u64, None, None),
+            None if start == events.end => (end, None, None),
+            // the feed hasn't got that far yet
+            None if start.0 == events.end.0 && start.1 > events.end.1 => {
+                (end, None, Some(start))
+            }
+            // the feed is somewhere the reader will get to
+            None if reader
+                .as_ref()
+                .is_some_and(|reader| reader.has_file(events.end.0)) =>
+            {
+                (end, Some(events.end), None)
+            }
+            // the feed hasn't yet seen a rotation the reader's past, so
+            // whatever it has left of the old file is from before they started
+            None => (end, None, Some(start)),
+        };
+        let id = events.next_subscriber;
+        events.next_subscriber += 1;
+        events.next.insert(id, next);
+        let catch_up = stop.and_then(|stop| {
+            let mut reader = reader?;
+            reader.stop = Some(stop);
+            Some(reader)
+        });
+        let mut rx = self.tx.subscribe();
+        rx.mark_changed();
+        let feed = self.clone();
+        let subscriber =
+            Subscriber { feed, id, catch_up, skip_to, lines_read: 0, shift: None };
+        (subscriber, rx)
+    }
+
+    /// Read on up to len, for all the feed's subscribers.  If there's more
+    /// to go the watch is re-armed, so a big catch-up goes out as several
+    /// tail notifications with other requests handled in between.
+    async fn read(&self, len: u64) -> Result<()> {
+        let mut reader = self.reader.lock().await;
+        let (read, done) = reader.read_to(len).await?;
+        let mut events = self.events.lock().unwrap();
+        events.queue.extend(read);
+        events.end = reader.at;
+        events.trim();
+        if !done {
+            self.rearm();
+        }
+        Ok(())
+    }
+
+    fn rearm(&self) {
+        self.tx.send_modify(|_| {});
+    }
+
+    /// A subscriber to take over from one that's fallen behind what the
+    /// feed keeps, reading on their own from where the next line starts
+    async fn resubscribe(self: &Arc<Self>, at: Position) -> Result<Subscriber> {
+        let (mut reader, _) = tokio::task::spawn_blocking({
+            let indexes = self.indexes.clone();
+            let logset = self.logset.clone();
+            move || Reader::open(&indexes, &logset)
+        })
+        .await??;
+        if !reader.seek_to(at) {
+            bail!(
+                "fell behind the feed for {}, which has since rotated",
+                reader.file.display()
+            );
+        }
+        Ok(self.subscribe(Some(reader)).0)
+    }
+}
+
+/// Someone's place in a feed, given up when dropped
+#[derive(Debug)]
+pub struct Subscriber {
+    feed: Arc<Feed>,
+    id: u64,
+    /// Reading on their own up to where the feed was on subscribing
+    catch_up: Option<Reader>,
+    /// Where they started, if the feed hadn't got there yet; what comes
+    /// before it is skipped
+    skip_to: Option<Position>,
+    /// Lines handed on so far
+    lines_read: usize,
+    /// What to add to the feed's count of lines to get ours, once we're
+    /// taking the feed's
+    shift: Option<isize>,
+}
+
+impl Subscriber {
+    /// The lines read since last time, up to len if it's down to us to read
+    /// them.  Logical lines keep coming across rotations.
+    pub async fn read(&mut self, len: u64) -> Result<Vec<FeedEvent>> {
+        if self.catch_up.is_none() {
+            self.feed.read(len).await?;
+            let taken = self.feed.events.lock().unwrap().take(self.id);
+            match taken {
+                Some(events) => return Ok(self.hand_on(events)),
+                None => self.fall_back().await?,
+            }
+        }
+        let Some(reader) = &mut self.catch_up else {
+            // picking up where the feed is next time
+            self.feed.rearm();
+            return Ok(vec![]);
+        };
+        let (events, done) = reader.read_to(len).await?;
+        // until it gets there it carries on on its own, which is slower
+        // but still right
+        let caught_up = reader.stopped();
+        if caught_up {
+            debug!("caught up with the feed for {}", reader.file.display());
+            self.catch_up = None;
+        }
+        // either there's more to catch up on, or what the feed has
+        if !done || caught_up {
+            self.feed.rearm();
+        }
+        self.lines_read +=
+            events.iter().filter(|ev| matches!(ev, FeedEvent::Line(_))).count();
+        Ok(events)
+    }
+
+    /// Having fallen behind what the feed keeps, carry on from our next line
+    /// on our own
+    async fn fall_back(&mut self) -> Result<()> {
+        let at = self.feed.events.lock().unwrap().behind[&self.id];
+        debug!("fell behind the feed for {}", self.feed.logset.current().display());
+        let lines_read = self.lines_read;
+        *self = self.feed.resubscribe(at).await?;
+        self.lines_read = lines_read;
+        Ok(())
+    }
+
+    /// Events taken from the feed, less any from before where we started
+    fn hand_on(&mut self, mut events: Vec<FeedEvent>) -> Vec<FeedEvent> {
+        if let Some((id, offset)) = self.skip_to {
+            let skip = events
+                .iter()
+                .take_while(|ev| {
+                    !matches!(ev, FeedEvent::Line(line) if line.at.0 == id && line.at.1 >= offset)
+                })
+                .count();
+            events.drain(..skip);
+            if !events.is_empty() {
+                self.skip_to = None;
+            }
+        }
+        for ev in &events {
+            if let FeedEvent::Line(line) = ev {
+                self.shift.get_or_insert(self.lines_read as isize - line.n as isize);
+                self.lines_read += 1;
+            }
+        }
+        events
+    }
+
+    /// The lines handed on so far that the feed still has, in order, by our
+    /// count of lines
+    pub fn kept(&self) -> Vec<(usize, ParsedLine)> {
+        let Some(shift) = self.shift else {
+            return vec![];
+        };
+        let events = self.feed.events.lock().unwrap();
+        let Some(next) = events.next.get(&self.id) else {
+            return vec![];
+        };
+        let taken = (next - events.first) as usize;
+        events
+            .queue
+            .range(..taken)
+            .filter_map(|ev| match ev {
+                FeedEvent::Line(line) => {
+                    let n = usize::try_from(line.n as isize + shift).ok()?;
+                    Some((n, line.parsed.clone()))
+                }
+                FeedEvent::Rotated(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut events = self.feed.events.lock().unwrap();
+        events.next.remove(&self.id);
+        events.behind.remove(&self.id);
+    }
+}
+
+/// Everyone's feeds, by the logset's current file
+#[derive(Debug, Default)]
+pub struct Feeds {
+    indexes: Arc<Indexes>,
+    feeds: Mutex<HashMap<PathBuf, Vec<Weak<Feed>>>>,
+}
+
+impl Feeds {
+    pub fn indexes(&self) -> &Arc<Indexes> {
+        &self.indexes
+    }
+
+    /// Subscribe to the logset's feed from wherever reader is, starting the
+    /// feed there if nobody's subscribed yet
+    pub fn subscribe(
+        &self,
+        logset: &Logset,
+        reader: Reader,
+    ) -> Result<(Subscriber, watch::Receiver<Option<u64>>)> {
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.retain(|_, feeds| {
+            feeds.retain(|feed| feed.strong_count() > 0);
+            !feeds.is_empty()
+        });
+        let same = feeds.get(&reader.file).and_then(|feeds| {
+            feeds.iter().filter_map(Weak::upgrade).find(|feed| {
+                feed.logset.kind == logset.kind
+                    && feed.logset.format == logset.format
+                    && feed.live()
+            })
+        });
+        Ok(match same {
+            Some(feed) => feed.subscribe(Some(reader)),
+            None => {
+                let file = reader.file.clone();
+                let feed = Arc::new(Feed::new(logset, reader)?);
+                feeds.entry(file).or_default().push(Arc::downgrade(&feed));
+                feed.subscribe(None)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Format;
+    use std::io::Write;
+
+    fn subscribe(
+        feeds: &Feeds,
+        logset: &Logset,
+        backfill_from: Option<u64>,
+    ) -> Result<Subscriber> {
+        let (mut reader, _) = Reader::open(feeds.indexes(), logset)?;
+        if let Some(offset) = backfill_from {
+            reader.seek(0, offset);
+        }
+        Ok(feeds.subscribe(logset, reader)?.0)
+    }
+
+    async fn texts(sub: &mut Subscriber) -> Result<Vec<String>> {
+        let mut texts = vec![];
+        // until caught up
+        for _ in 0..3 {
+            for ev in sub.read(u64::MAX).await? {
+                match ev {
+                    FeedEvent::Line(line) => texts.push(line.parsed.text),
+                    FeedEvent::Rotated(r) => texts.push(format!("{r:?}")),
+                }
+            }
+        }
+        Ok(texts)
+    }
+
+    #[tokio::test]
+    async fn test_feeds() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        std::fs::write(&path, "a\nb\nc\n")?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let feeds = Feeds::default();
+        // the first starts the feed where it starts
+        let mut first = subscribe(&feeds, &logset, Some(4))?;
+        assert_eq!(texts(&mut first).await?, vec!["c"]);
+        // later ones share it, whether starting from what it's read already,
+        // from further back, or from further on than it's got to yet
+        let mut queued = subscribe(&feeds, &logset, Some(4))?;
+        let mut behind = subscribe(&feeds, &logset, None)?;
+        let mut f = std::fs::OpenOptions::new().append(true).open(&path)?;
+        f.write_all(b"d\n")?;
+        let mut ahead = subscribe(&feeds, &logset, Some(8))?;
+        f.write_all(b"e\n")?;
+        assert!(
+            Arc::ptr_eq(&first.feed, &ahead.feed)
+                && Arc::ptr_eq(&first.feed, &behind.feed)
+        );
+        assert_eq!(texts(&mut first).await?, vec!["d", "e"]);
+        assert_eq!(texts(&mut queued).await?, vec!["c", "d", "e"]);
+        assert_eq!(texts(&mut behind).await?, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(texts(&mut ahead).await?, vec!["e"]);
+        // a differently formatted logset gets its own
+        let other = Logset { format: Some(Format::Plain), ..logset.clone() };
+        let plain = subscribe(&feeds, &other, None)?;
+        assert!(!Arc::ptr_eq(&first.feed, &plain.feed));
+        // and once everyone's gone, so's the feed
+        let feed = Arc::downgrade(&first.feed);
+        drop((first, queued, behind, ahead));
+        assert!(feed.upgrade().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_rotation() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "a\nb\n")?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let feeds = Feeds::default();
+        let mut first = subscribe(&feeds, &logset, None)?;
+        assert_eq!(texts(&mut first).await?, vec!["a", "b"]);
+        // rotated before the feed has had a look
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"c")?;
+        std::fs::rename(&path, dir.path().join("app.log.1"))?;
+        std::fs::write(&path, "d\n")?;
+        let mut late = subscribe(&feeds, &logset, None)?;
+        assert!(Arc::ptr_eq(&first.feed, &late.feed));
+        assert_eq!(texts(&mut late).await?, vec!["d"]);
+        assert_eq!(texts(&mut first).await?, vec!["c", "Replaced", "d"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stalled_subscriber() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("current");
+        let numbers =
+            |n: std::ops::Range<usize>| n.map(|n| n.to_string()).collect::<Vec<_>>();
+        let lines = |n| numbers(n).into_iter().map(|n| n + "\n").collect::<String>();
+        std::fs::write(&path, lines(0..KEEP_EVENTS / 2))?;
+        let logset = Logset { path: path.clone(), kind: LogsetKind::File, format: None };
+        let feeds = Feeds::default();
+        let mut reading = subscribe(&feeds, &logset, None)?;
+        let mut stalled = subscribe(&feeds, &logset, None)?;
+        assert_eq!(texts(&mut reading).await?, numbers(0..KEEP_EVENTS / 2));
+        // the feed doesn't hold on to more for the one that's stalled
+        let mut f = std::fs::OpenOptions::new().append(true).open(&path)?;
+        f.write_all(lines(KEEP_EVENTS / 2..KEEP_EVENTS + 10).as_bytes())?;
+        assert_eq!(
+            texts(&mut reading).await?,
+            numbers(KEEP_EVENTS / 2..KEEP_EVENTS + 10)
+        );
+        assert_eq!(reading.feed.events.lock().unwrap().queue.len(), KEEP_EVENTS);
+        // which reads what it missed on its own, then shares the feed again
+        assert_eq!(texts(&mut stalled).await?, numbers(0..KEEP_EVENTS + 10));
+        assert!(Arc::ptr_eq(&stalled.feed, &reading.feed) && stalled.catch_up.is_none());
+        f.write_all(b"more\n")?;
+        assert_eq!(texts(&mut stalled).await?, vec!["more"]);
+        assert_eq!(texts(&mut reading).await?, vec!["more"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+//! Sparse line-offset indexes over log files, so that a logical line can be
+//! found without reading every line before it.
+//!
+//! Indexes are kept per file, by device and inode so that they survive
+//! renames, for as long as something holds on to them and for IDLE_TTL
+//! after that.  They're built in the background and then kept up to date by
+//! whoever reads the file.
+
+use crate::config::Logset;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Lines between checkpoints
+const STRIDE: usize = 1024;
+/// Bytes read per step when building in the background
+const BUILD_CHUNK: usize = 1 << 20;
+/// Seeking by time stops bisecting once it's narrowed down to this many
+/// bytes, and scans linearly from there
+const SEEK_WINDOW: u64 = 64 * 1024;
+/// How far before the bisected position to start the linear scan, to catch
+/// lines that are a little out of order
+const SEEK_SLACK: u64 = 64 * 1024;
+/// Lines read at a time when probing for timestamps
+const SEEK_PROBE_LINES: usize = 64;
+/// How long to keep an index around after the last user lets go of it
+const IDLE_TTL: Duration = Duration::from_secs(600);
+
+pub type FileId = (u64, u64);
+
+pub fn file_id(meta: &std::fs::Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[derive(Debug)]
+pub struct LineIndex {
+    /// Offset of the start of every STRIDE'th line, starting with line 0
+    checkpoints: Vec<u64>,
+    /// Bytes indexed so far
+    indexed: u64,
+    /// Newlines seen in the indexed bytes
+    lines: usize,
+    building: bool,
+}
+
+impl LineIndex {
+    fn new() -> Self {
+        LineIndex { checkpoints: vec![0], indexed: 0, lines: 0, building: false }
+    }
+
+    /// Index bytes that were read starting at offset at.  Whatever overlaps
+    /// what's already indexed is skipped; bytes after a gap are ignored.
+    pub fn extend(&mut self, at: u64, bytes: &[u8]) {
+        if at > self.indexed || at + bytes.len() as u64 <= self.indexed {
+            return;
+        }
+        let skip = (self.indexed - at) as usize;
+        for (i, b) in bytes[skip..].iter().enumerate() {
+            if *b == b'\n' {
+                self.lines += 1;
+                if self.lines.is_multiple_of(STRIDE) {
+                    self.checkpoints.push(self.indexed + i as u64 + 1);
+                }
+            }
+        }
+        self.indexed += (bytes.len() - skip) as u64;
+    }
+
+    pub fn indexed(&self) -> u64 {
+        self.indexed
+    }
+
+    /// Complete lines in the indexed bytes
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Where to start reading to reach line n, as an offset and a number
+    /// of lines to skip from there
+    pub fn seek_line(&self, n: usize) -> Option<(u64, usize)> {
+        (n <= self.lines).then(|| (self.checkpoints[n / STRIDE], n % STRIDE))
+    }
+
+    /// The line number that the line starting at offset would have
+    pub fn line_at(&self, f: &File, offset: u64) -> Result<Option<usize>> {
+        if offset > self.indexed {
+            return Ok(None);
+        }
+        let k = self.checkpoints.partition_point(|c| *c <= offset) - 1;
+        let mut buf = vec![0u8; (offset - self.checkpoints[k]) as usize];
+        f.read_exact_at(&mut buf, self.checkpoints[k])?;
+        Ok(Some(k * STRIDE + buf.iter().filter(|b| **b == b'\n').count()))
+    }
+}
+
+/// An index, and when it was last asked for
+type Entry = (Arc<Mutex<LineIndex>>, Instant);
+
+#[derive(Debug, Default)]
+pub struct Indexes {
+    files: Mutex<HashMap<FileId, Entry>>,
+}
+
+impl Indexes {
+    /// A fresh index for an open file in place of whatever there was, for
+    /// when it's been truncated and has grown back past the old one
+    pub fn reset(&self, f: &File) -> Result<Arc<Mutex<LineIndex>>> {
+        self.files.lock().unwrap().remove(&file_id(&f.metadata()?));
+        self.get(f)
+    }
+
+    /// Get the index for an open file, starting a background build if the
+    /// index is behind the end of the file
+    pub fn get(&self, f: &File) -> Result<Arc<Mutex<LineIndex>>> {
+        let meta = f.metadata()?;
+        let id = file_id(&meta);
+        let index = {
+            let mut files = self.files.lock().unwrap();
+            let now = Instant::now();
+            files.retain(|_, (index, last_used)| {
+                Arc::strong_count(index) > 1 || now - *last_used < IDLE_TTL
+            });
+            match files.get_mut(&id) {
+                // if the file shrank, it was truncated and needs a fresh index
+                Some((index, last_used))
+                    if index.lock().unwrap().indexed <= meta.len() =>
+                {
+                    *last_used = now;
+                    index.clone()
+                }
+                _ => {
+                    let index = Arc::new(Mutex::new(LineIndex::new()));
+                    files.insert(id, (index.clone(), now));
+                    index
+                }
+            }
+        };
+        let behind = {
+            let mut index = index.lock().unwrap();
+            let behind = !index.building && index.indexed < meta.len();
+            index.building |= behind;
+            behind
+        };
+        if behind {
+            let f = f.try_clone()?;
+            let index = index.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = build(&f, &index) {
+                    error!("while building line index: {e}");
+                }
+                let mut index = index.lock().unwrap();
+                index.building = false;
+                debug!("line index built, {} lines", index.lines);
+            });
+        }
+        Ok(index)
+    }
+
+    /// Get the indexes for every file in a logset, oldest first
+    pub fn logset(&self, logset: &Logset) -> Result<LogsetIndex> {
+        let mut segments = vec![];
+        for path in logset.archives()?.into_iter().chain([logset.current()]) {
+            let f = File::open(&path)?;
+            let len = f.metadata()?.len();
+            let index = self.get(&f)?;
+            segments.push(Segment { path, len, index });
+        }
+        Ok(LogsetIndex { segments })
+    }
+}
+
+fn build(f: &File, index: &Mutex<LineIndex>) -> Result<()> {
+    let mut buf = vec![0u8; BUILD_CHUNK];
+    loop {
+        let at = index.lock().unwrap().indexed;
+        let n = f.read_at(&mut buf, at)?;
+        if n == 0 {
+            return Ok(());
+        }
+        index.lock().unwrap().extend(at, &buf[..n]);
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    /// Length when the logset was listed
+    len: u64,
+    index: Arc<Mutex<LineIndex>>,
+}
+
+/// Line indexes across all the files of a logset, numbering lines as one
+/// continuous stream over whatever files are currently on disk
+#[derive(Debug)]
+pub struct LogsetIndex {
+    segments: Vec<Segment>,
+}
+
+impl LogsetIndex {
+    /// Whether any of the files are still being indexed in the background
+    pub fn building(&self) -> bool {
+        self.segments.iter().any(|seg| seg.index.lock().unwrap().building)
+    }
+
+    /// Find logical line n, as a segment, offset and lines to skip from
+    /// there.  None if the index doesn't reach that far (yet).
+    fn locate(&self, n: usize) -> Option<(usize, u64, usize)> {
+        let mut base = 0;
+        for (i, seg) in self.segments.iter().enumerate() {
+            let index = seg.index.lock().unwrap();
+            let last = i == self.segments.len() - 1;
+            if n < base + index.lines || last {
+                return index.seek_line(n - base).map(|(offset, skip)| (i, offset, skip));
+            }
+            if index.indexed < seg.len {
+                // still being built
+                return None;
+            }
+            base += index.lines;
+        }
+        None
+    }
+
+    /// Total bytes across the logset, as of when it was listed
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|seg| seg.len).sum()
+    }
+
+    /// Total complete lines across the logset, if it's all been indexed
+    pub fn total_lines(&self) -> Option<usize> {
+        let mut n = 0;
+        for seg in &self.segments {
+            let index = seg.index.lock().unwrap();
+            if index.indexed < seg.len {
+                return None;
+            }
+            n += index.lines;
+        }
+        Some(n)
+    }
+
+    /// Logical line number of the line starting at offset into a segment
+    fn lln_at(&self, seg: usize, offset: u64) -> Result<Option<usize>> {
+        let mut n = 0;
+        for s in &self.segments[..seg] {
+            let index = s.index.lock().unwrap();
+            if index.indexed < s.len {
+                return Ok(None);
+            }
+            n += index.lines;
+        }
+        let f = File::open(&self.segments[seg].path)?;
+        let line = self.segments[seg].index.lock().unwrap().line_at(&f, offset)?;
+        Ok(line.map(|line| n + line))
+    }
+
+    /// Read up to count complete lines starting at logical line n.
+    /// None if the index doesn't reach line n yet.
+    pub fn lines(&self, n: usize, count: usize) -> Result<Option<Lines>> {
+        match self.locate(n) {
+            Some((seg, offset, skip)) => {
+                let (lines, next) = self.read(seg, offset, skip, count)?;
+                let lines = lines.into_iter().map(|(_, line)| line).collect();
+                Ok(Some(Lines { lln: Some(n), lines, next }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like lines, but if the index doesn't reach line n yet and is still
+    /// being built, wait for it to
+    pub async fn wait_lines(&self, n: usize, count: usize) -> Result<Option<Lines>> {
+        loop {
+            let building = self.building();
+            if let Some(lines) = self.lines(n, count)? {
+                return Ok(Some(lines));
+            }
+            if !building {
+                return Ok(None);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Which segment a cursor points into, and the offset into it
+    fn segment_at(&self, cursor: u64) -> (usize, u64) {
+        let mut base = 0;
+        let last = self.segments.len() - 1;
+        for (i, seg) in self.segments[..last].iter().enumerate() {
+            if cursor < base + seg.len {
+                return (i, cursor - base);
+            }
+            base += seg.len;
+        }
+        (last, cursor - base)
+    }
+
+    /// The file cursor points into, and the offset into that file
+    pub fn file_at(&self, cursor: u64) -> (&Path, u64) {
+        let (seg, offset) = self.segment_at(cursor);
+        (&self.segments[seg].path, offset)
+    }
+
+    /// The first line boundary at or after cursor
+    fn align(&self, cursor: u64) -> Result<u64> {
+        let (seg, offset) = self.segment_at(cursor);
+        if offset == 0 {
+            return Ok(cursor);
+        }
+        // back up a byte and skip a line, so that a cursor that's already
+        // on a line boundary stays there
+        let (_, next) = self.read(seg, offset - 1, 1, 0)?;
+        Ok(next)
+    }
+
+    /// Read up to count complete lines starting at the first line boundary
+    /// at or after cursor, a byte offset into the logset as a whole
+    pub fn lines_at(&self, cursor: u64, count: usize) -> Result<Lines> {
+        let (seg, offset) = self.segment_at(self.align(cursor)?);
+        let lln = self.lln_at(seg, offset)?;
+        let (lines, next) = self.read(seg, offset, 0, count)?;
+        let lines = lines.into_iter().map(|(_, line)| line).collect();
+        Ok(Lines { lln, lines, next })
+    }
+
+    /// Find the cursor of the first line timestamped at or after t, by
+    /// binary search over byte offsets, so as not to read the whole logset.
+    ///
+    /// Lines without timestamps are skipped over.  Logs are assumed to be
+    /// mostly in time order; lines that are out of order by less than
+    /// SEEK_SLACK bytes are still found.
+    pub fn seek_time(
+        &self,
+        t: DateTime<Utc>,
+        ts_of: impl Fn(&str) -> Option<DateTime<Utc>>,
+    ) -> Result<u64> {
+        // the first timestamped line starting in [cursor, limit)
+        let probe = |cursor: u64, limit: u64| -> Result<Option<(u64, DateTime<Utc>)>> {
+            let (seg, offset) = self.segment_at(self.align(cursor)?);
+            let (lines, _) = self.read(seg, offset, 0, SEEK_PROBE_LINES)?;
+            for (start, line) in lines {
+                if start >= limit {
+                    break;
+                }
+                if let Some(ts) = ts_of(&line) {
+                    return Ok(Some((start, ts)));
+                }
+            }
+            Ok(None)
+        };
+        let (mut lo, mut hi) = (0, self.len());
+        while hi - lo > SEEK_WINDOW {
+            let mid = lo + (hi - lo) / 2;
+            match probe(mid, hi)? {
+                Some((start, ts)) if ts < t => lo = start,
+                _ => hi = mid,
+            }
+        }
+        // scan forward from a bit before where the search ended up; if
+        // nothing turns up by a bit after, hi is as good a guess as any
+        let mut cursor = self.align(lo.saturating_sub(SEEK_SLACK))?;
+        while cursor <= hi + SEEK_WINDOW {
+            let (seg, offset) = self.segment_at(cursor);
+            let (lines, next) = self.read(seg, offset, 0, SEEK_PROBE_LINES)?;
+            for (start, line) in &lines {
+                if ts_of(line).is_some_and(|ts| ts >= t) {
+                    return Ok(*start);
+                }
+            }
+            if lines.is_empty() {
+                break;
+            }
+            cursor = next;
+        }
+        self.align(hi)
+    }
+
+    /// Read up to count complete lines from offset into segment seg, after
+    /// skipping skip lines, returning the lines with their cursors and the
+    /// cursor after them
+    fn read(
+        &self,
+        seg: usize,
+        mut offset: u64,
+        mut skip: usize,
+        count: usize,
+    ) -> Result<(Vec<(u64, String)>, u64)> {
+        let mut base = self.segments[..seg].iter().map(|s| s.len).sum::<u64>();
+        let mut next = base + offset;
+        let mut lines = vec![];
+        let last = self.segments.len() - 1;
+        for (i, s) in self.segments.iter().enumerate().skip(seg) {
+            let mut f = File::open(&s.path)?;
+            f.seek(SeekFrom::Start(offset))?;
+            let mut r = BufReader::new(f);
+            let mut buf = vec![];
+            while skip > 0 || lines.len() < count {
+                buf.clear();
+                if r.read_until(b'\n', &mut buf)? == 0 || !buf.ends_with(b"\n") {
+                    break;
+                }
+                let start = next;
+                next += buf.len() as u64;
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+                let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                lines.push((start, String::from_utf8_lossy(line).into_owned()));
+            }
+            if skip == 0 && lines.len() == count {
+                break;
+            }
+            base += s.len;
+            if i == last || next < base {
+                // stopped short of the end of a segment, on a partial line
+                break;
+            }
+            offset = 0;
+        }
+        Ok((lines, next))
+    }
+}
+
+/// Lines read out of a logset
+#[derive(Debug)]
+pub struct Lines {
+    /// Logical line number of the first line, if the index knows it
+    pub lln: Option<usize>,
+    pub lines: Vec<String>,
+    /// Cursor just past the last line read
+    pub next: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::LogsetKind, parser::LineParser};
+
+    #[tokio::test]
+    async fn test_line_index() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let contents = (0..5000).map(|i| format!("line {i}\n")).collect::<String>();
+        let (older, newer) = contents.split_at(contents.find("line 3000\n").unwrap());
+        std::fs::write(dir.path().join("@400000006a1b2c3d00000000.s"), older)?;
+        std::fs::write(dir.path().join("current"), newer)?;
+        let logset =
+            Logset { path: dir.path().to_path_buf(), kind: LogsetKind::S6, format: None };
+        let indexes = Indexes::default();
+        let index = indexes.logset(&logset)?;
+        while index.building() {
+            tokio::task::yield_now().await;
+        }
+        let lines = index.lines(2998, 4)?.unwrap();
+        assert_eq!(lines.lines, vec!["line 2998", "line 2999", "line 3000", "line 3001"]);
+        let lines = index.lines_at(lines.next, 2)?;
+        assert_eq!(lines.lines, vec!["line 3002", "line 3003"]);
+        assert_eq!(lines.lln, Some(3002));
+        let lines = index.lines_at(lines.next - 3, 1)?;
+        assert_eq!(lines.lines, vec!["line 3004"]);
+        assert_eq!(lines.lln, Some(3004));
+        let lines = index.lines(4998, 4)?.unwrap();
+        assert_eq!(lines.lines, vec!["line 4998", "line 4999"]);
+        assert_eq!(lines.next, index.len());
+        assert!(index.lines(5001, 1)?.is_none());
+        assert_eq!(index.total_lines(), Some(5000));
+        // indexes are shared, and kept up to date by readers
+        let f = File::open(dir.path().join("current"))?;
+        let current = indexes.get(&f)?;
+        assert!(Arc::ptr_eq(&current, &index.segments[1].index));
+        let mut current = current.lock().unwrap();
+        current.extend(newer.len() as u64 - 7, b"e 4999\nline 5000\n");
+        assert_eq!(current.lines(), 2001);
+        assert_eq!(
+            current.line_at(&f, newer.find("line 4000").unwrap() as u64)?,
+            Some(1000)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seek_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let t0: DateTime<Utc> = "2024-02-25T00:00:00Z".parse()?;
+        let ts = |i: i64| t0 + chrono::Duration::seconds(i);
+        let mut contents = String::new();
+        for i in 0..20_000 {
+            // a few lines out of order, and some continuation lines
+            let t = if i % 1000 == 7 { ts(i - 3) } else { ts(i) };
+            contents.push_str(&format!("[{} INFO test] line {i}\n", t.to_rfc3339()));
+            if i % 10 == 0 {
+                contents.push_str("  continued\n");
+            }
+        }
+        let older = &contents[..contents.find("line 12345\n").unwrap()];
+        let older = &older[..=older.rfind('\n').unwrap()];
+        let newer = &contents[older.len()..];
+        std::fs::write(dir.path().join("@400000006a1b2c3d00000000.s"), older)?;
+        std::fs::write(dir.path().join("current"), newer)?;
+        let logset =
+            Logset { path: dir.path().to_path_buf(), kind: LogsetKind::S6, format: None };
+        let index = Indexes::default().logset(&logset)?;
+        let seek = |t| -> Result<String> {
+            let cursor =
+                index.seek_time(t, |line| LineParser::EnvLogger.timestamp(line))?;
+            let line = index.lines_at(cursor, 1)?.lines.pop().unwrap_or_default();
+            Ok(line.split_once("] ").map(|(_, l)| l.to_string()).unwrap_or(line))
+        };
+        assert_eq!(seek(ts(-100))?, "line 0");
+        assert_eq!(seek(ts(5000))?, "line 5000");
+        assert_eq!(seek(ts(12345))?, "line 12345");
+        assert_eq!(seek(ts(12345) + chrono::Duration::milliseconds(1))?, "line 12346");
+        assert_eq!(seek(ts(3004))?, "line 3004");
+        assert_eq!(seek(ts(3005))?, "line 3005");
+        assert_eq!(seek(ts(19_999))?, "line 19999");
+        assert_eq!(seek(ts(20_000))?, "");
+        Ok(())
+    }
+}
@@ -1,50 +1,190 @@
-// CR alee: this is a bastardized incomplete impl of the nominal JSON-RPC
-// spec but I don't care until there's an actual need for interop
+//! JSON-RPC 2.0, with batches and notifications.  Requests may leave out
+//! `jsonrpc`, and the UI's do, but responses and notifications always say
+//! "2.0".
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// Error codes: the spec's, then ours, from the range it leaves to servers
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+/// No logset by that name in the config
+pub const LOGSET_NOT_FOUND: i32 = -32001;
+/// The filter regex or query doesn't parse; data.column says where, counting
+/// characters from 1
+pub const BAD_FILTER: i32 = -32002;
+/// No subscription by that id, e.g. for resize, or it's done
+pub const NO_SUBSCRIPTION: i32 = -32003;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Method {
     /// Request to list all available logsets
     List,
-    /// Request to change which logset to display and tail
+    /// Request to display and tail a logset, as a new subscription
     Logs,
-    /// Notification from the server, additional display lines
+    /// Request to display and tail several logsets as one, ordered by
+    /// timestamp, as a new subscription
+    Merge,
+    /// Request to rewrap what's been sent for a subscription at a different
+    /// width, without reading it all again
+    Resize,
+    /// Request for a window of a logset's history, by logical line or
+    /// byte offset, independent of tailing
+    Range,
+    /// Request to stop tailing a subscription
+    Unsubscribe,
+    /// Notification from the server, additional display lines for a
+    /// subscription
     Tail,
+    /// Notification from the server that the logset was rotated or
+    /// truncated; tailing continues from the start of the new file
+    Rotated,
     /// Notification form the server that the logset has fused,
     /// or no more tailing is possible
     Done,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RequestHeader {
-    pub id: u64,
-    pub method: Method,
+/// Serializes as "2.0", the only version there is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Version;
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("2.0")
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Request<T> {
-    pub id: u64,
-    pub method: Method,
-    pub params: T,
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "2.0" => Ok(Version),
+            v => {
+                Err(serde::de::Error::custom(format!("unsupported jsonrpc version {v}")))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(serde_json::Number),
+    String(String),
+    Null,
+}
+
+impl From<u64> for Id {
+    fn from(n: u64) -> Self {
+        Id::Number(n.into())
+    }
+}
+
+/// A request, before its params are looked at
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    /// Only there to check it's "2.0", if given
+    #[serde(default, rename = "jsonrpc")]
+    _jsonrpc: Option<Version>,
+    /// None for a notification, which gets no response; a null id is
+    /// Some(Id::Null)
+    #[serde(default, deserialize_with = "present")]
+    pub id: Option<Id>,
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Id>, D::Error> {
+    Id::deserialize(deserializer).map(Some)
+}
+
+impl Request {
+    pub fn method(&self) -> Result<Method, Error> {
+        Method::deserialize(serde_json::Value::String(self.method.clone())).map_err(
+            |_| Error::new(METHOD_NOT_FOUND, format!("no method {}", self.method)),
+        )
+    }
+
+    pub fn params<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        T::deserialize(&self.params).map_err(|e| Error::new(INVALID_PARAMS, e))
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Notification<T> {
+    pub jsonrpc: Version,
     pub method: Method,
     pub params: T,
 }
 
+impl<T> Notification<T> {
+    pub fn new(method: Method, params: T) -> Self {
+        Notification { jsonrpc: Version, method, params }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Response<T> {
-    pub id: u64,
-    pub result: Option<T>,
-    pub error: Option<Error>,
+    pub jsonrpc: Version,
+    /// Null if the request was too broken to tell
+    pub id: Id,
+    #[serde(flatten)]
+    pub outcome: Outcome<T>,
+}
+
+/// Either a result or an error, never both
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome<T> {
+    Result(T),
+    Error(Error),
+}
+
+impl<T> Response<T> {
+    pub fn result(id: Id, result: T) -> Self {
+        Response { jsonrpc: Version, id, outcome: Outcome::Result(result) }
+    }
+
+    pub fn error(id: Id, error: Error) -> Self {
+        Response { jsonrpc: Version, id, outcome: Outcome::Error(error) }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Error {
     pub code: i32,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl Error {
+    pub fn new(code: i32, message: impl fmt::Display) -> Self {
+        Error { code, message: message.to_string(), data: None }
+    }
+
+    pub fn with_data(self, data: serde_json::Value) -> Self {
+        Error { data: Some(data), ..self }
+    }
+
+    /// The error to respond with for any error: itself if it's one of ours
+    /// already, or else an internal error
+    pub fn from_anyhow(e: anyhow::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::new(INTERNAL_ERROR, format!("{e:#}")),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
 }
+
+impl std::error::Error for Error {}
@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use clap::{Args, Parser, Subcommand};
 use log::error;
 use rand::Rng;
@@ -7,10 +8,16 @@ use serde::Deserialize;
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use warp::Filter;
 
+mod ansi;
 mod config;
 mod connection;
+mod feed;
+mod index;
 mod json_rpc;
+mod merge;
 mod parser;
+mod query;
+mod s6;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -25,6 +32,8 @@ enum Command {
     Babble(BabbleArgs),
     /// Run the log server
     Server(ServerArgs),
+    /// Print logical lines from a log file, via the line index
+    Lines(LinesArgs),
     /// Test tailing a log file
     Tail(TailArgs),
 }
@@ -33,6 +42,10 @@ enum Command {
 struct BabbleArgs {
     #[arg(long, short_alias = 'n', default_value = "100")]
     lines: usize,
+    /// Mix in wide and zero width graphemes, e.g. CJK and emoji, to test
+    /// wrapping them
+    #[arg(long)]
+    unicode: bool,
 }
 
 #[derive(Args)]
@@ -43,12 +56,49 @@ struct ServerArgs {
     config: PathBuf,
 }
 
+#[derive(Args)]
+struct LinesArgs {
+    /// First logical line to print
+    #[arg(long)]
+    start: usize,
+    #[arg(long, default_value = "1")]
+    count: usize,
+    /// Treat log_file as an s6-log directory
+    #[arg(long)]
+    s6: bool,
+    log_file: PathBuf,
+}
+
 #[derive(Args, Debug, Clone, Deserialize)]
 struct TailArgs {
     #[arg(long)]
     cols: usize,
+    /// Indent wrapped rows to line up under the message
+    #[arg(long)]
+    hanging_indent: bool,
     #[arg(long)]
     filter: Option<String>,
+    /// Only show records the query matches, e.g. 'level>=warn AND /timeout/'
+    #[arg(long)]
+    query: Option<String>,
+    /// Only read the last n lines before tailing
+    #[arg(long, conflicts_with = "backfill_bytes")]
+    backfill_lines: Option<usize>,
+    /// Only read about the last n bytes before tailing
+    #[arg(long)]
+    backfill_bytes: Option<u64>,
+    /// Only show lines timestamped at or after this, e.g. 2024-03-01T14:02:00Z
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+    /// Only show lines timestamped before this
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+    /// Only show lines at least this severe, e.g. warn
+    #[arg(long)]
+    min_level: Option<log::Level>,
+    /// Treat log_file as an s6-log directory
+    #[arg(long)]
+    s6: bool,
     log_file: PathBuf,
 }
 
@@ -58,12 +108,26 @@ async fn main() -> Result<()> {
     match cli.command {
         Command::Babble(args) => babble(args)?,
         Command::Server(args) => server(args).await?,
+        Command::Lines(args) => lines(args).await?,
         Command::Tail(args) => tail(args).await?,
     }
     Ok(())
 }
 
-// TODO: generate random unicode emoji's too to test monospace graphemes
+/// Graphemes of all widths, for babble --unicode
+const BABBLE_GRAPHEMES: &[&str] = &[
+    "日本語",
+    "中文",
+    "한국어",
+    "🚀",
+    "🔥",
+    "👩\u{200d}💻",
+    "🇯🇵",
+    "e\u{301}",
+    "\u{200b}",
+    "ｆｕｌｌ",
+];
+
 fn babble(args: BabbleArgs) -> Result<()> {
     use log::{log, Level, LevelFilter};
     env_logger::builder().filter_level(LevelFilter::Trace).init();
@@ -76,6 +140,21 @@ fn babble(args: BabbleArgs) -> Result<()> {
         .take(args.lines);
     let mut rng = rand::thread_rng();
     for sentence in sentences {
+        let sentence = if args.unicode {
+            sentence
+                .split(' ')
+                .map(|word| match rng.gen_range(0..8) {
+                    0 => format!(
+                        "{word}{}",
+                        BABBLE_GRAPHEMES[rng.gen_range(0..BABBLE_GRAPHEMES.len())]
+                    ),
+                    _ => word.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            sentence
+        };
         let level = match rng.gen_range(0..=5) {
             0 => None,
             1 => Some(Level::Error),
@@ -97,31 +176,91 @@ async fn server(args: ServerArgs) -> Result<()> {
     // TODO: switch to toml to keep consistent with s7
     let config: Arc<config::Config> =
         Arc::new(serde_yaml::from_str(&std::fs::read_to_string(&args.config)?)?);
-    let routes = warp::any().map(move || config.clone()).and(warp::ws()).map(
-        |config: Arc<config::Config>, ws: warp::ws::Ws| {
-            ws.on_upgrade(|ws| async move {
-                if let Err(e) = connection::handle_ws(config, ws).await {
-                    error!("while handling websocket connection: {}", e);
-                }
-            })
-        },
-    );
+    // catch bad formats now rather than when someone opens the logset
+    for (name, logset) in &config.logsets {
+        if let Some(Err(e)) = logset.format.as_ref().map(parser::LineParser::new) {
+            bail!("bad format for logset {name}: {e}");
+        }
+    }
+    let feeds = Arc::new(feed::Feeds::default());
+    let routes =
+        warp::any().map(move || (config.clone(), feeds.clone())).and(warp::ws()).map(
+            |(config, feeds): (Arc<config::Config>, Arc<feed::Feeds>),
+             ws: warp::ws::Ws| {
+                ws.on_upgrade(|ws| async move {
+                    if let Err(e) = connection::handle_ws(config, feeds, ws).await {
+                        error!("while handling websocket connection: {}", e);
+                    }
+                })
+            },
+        );
     warp::serve(routes).run(args.bind).await;
     Ok(())
 }
 
+async fn lines(args: LinesArgs) -> Result<()> {
+    env_logger::init();
+    let logset = config::Logset {
+        path: args.log_file.clone(),
+        kind: if args.s6 { config::LogsetKind::S6 } else { config::LogsetKind::File },
+        format: None,
+    };
+    let indexes = index::Indexes::default();
+    let index = indexes.logset(&logset)?;
+    match index.wait_lines(args.start, args.count).await? {
+        Some(lines) => {
+            for line in lines.lines {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        None => bail!("no line {} in {}", args.start, args.log_file.display()),
+    }
+}
+
 async fn tail(args: TailArgs) -> Result<()> {
     env_logger::init();
-    let filter = args.filter.as_ref().map(|s| Regex::new(s)).transpose()?;
-    let (mut ctx, mut rx_tail) =
-        connection::Context::new(args.log_file.clone(), args.cols, filter)?;
+    let filter = parser::LineFilter {
+        regex: args.filter.as_ref().map(|s| Regex::new(s)).transpose()?,
+        since: args.since,
+        until: args.until,
+        min_level: args.min_level,
+        query: args.query.as_deref().map(query::Query::parse).transpose()?,
+    };
+    let logset = config::Logset {
+        path: args.log_file.clone(),
+        kind: if args.s6 { config::LogsetKind::S6 } else { config::LogsetKind::File },
+        format: None,
+    };
+    let backfill = match (args.backfill_lines, args.backfill_bytes) {
+        (Some(n), _) => Some(connection::Backfill::Lines(n)),
+        (None, Some(n)) => Some(connection::Backfill::Bytes(n)),
+        (None, None) => None,
+    };
+    let (mut ctx, mut rx_tail) = connection::Context::new(
+        &Default::default(),
+        &logset,
+        parser::Wrap { cols: args.cols, hanging_indent: args.hanging_indent },
+        filter,
+        backfill,
+    )
+    .await?;
     loop {
         rx_tail.changed().await?;
-        match { *rx_tail.borrow_and_update() } {
+        let changed = *rx_tail.borrow_and_update();
+        match changed {
             Some(len) => {
-                let inc = ctx.read_to(len).await?;
-                for line in inc {
-                    println!("{}", serde_json::to_string(&line)?);
+                for ev in ctx.read_to(len).await? {
+                    match ev {
+                        connection::TailEvent::Lines(lines) => {
+                            for line in lines {
+                                println!("{}", serde_json::to_string(&line)?);
+                            }
+                        }
+                        connection::TailEvent::Rotated(rotation) => {
+                            println!("file rotated: {:?}", rotation);
+                        }
+                    }
                 }
             }
             None => {
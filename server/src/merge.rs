@@ -0,0 +1,256 @@
+//! Several logsets' display lines interleaved by timestamp, for a merged
+//! view.  Each logset's lines are taken to be in order already, so it's a
+//! merge rather than a sort, and lines without a timestamp stay with the
+//! ones before them.
+//!
+//! While tailing, lines wait for the other logsets to catch up in case they
+//! have something earlier, but only for so long: after REORDER_WINDOW, or
+//! once MAX_WAITING_LINES are waiting, the earliest go out anyway.
+
+use crate::parser::{DisplayLine, Span, SpanLabel};
+use chrono::{DateTime, Utc};
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+use unicode_width::UnicodeWidthStr;
+
+pub const REORDER_WINDOW: Duration = Duration::from_secs(2);
+const MAX_WAITING_LINES: usize = 10_000;
+
+/// A record's display lines
+#[derive(Debug)]
+struct Chunk {
+    record: usize,
+    ts: Option<DateTime<Utc>>,
+    lines: Vec<DisplayLine>,
+    arrived: Instant,
+}
+
+#[derive(Debug)]
+struct Source {
+    name: String,
+    chunks: VecDeque<Chunk>,
+    /// For records with no timestamp of their own
+    last_ts: Option<DateTime<Utc>>,
+    /// No more lines are coming, so there's no waiting for it
+    done: bool,
+}
+
+#[derive(Debug)]
+pub struct Merge {
+    sources: Vec<Source>,
+    /// Width the names are padded to, or 0 for just the one source, which
+    /// isn't labelled
+    name_width: usize,
+    waiting: usize,
+}
+
+impl Merge {
+    pub fn new(names: &[String]) -> Self {
+        let name_width = match names {
+            [_] => 0,
+            _ => names.iter().map(|name| name.width()).max().unwrap_or(0),
+        };
+        let sources = names
+            .iter()
+            .map(|name| Source {
+                name: name.clone(),
+                chunks: VecDeque::new(),
+                last_ts: None,
+                done: false,
+            })
+            .collect();
+        Merge { sources, name_width, waiting: 0 }
+    }
+
+    /// Columns the source label takes up at the start of each line
+    pub fn label_width(&self) -> usize {
+        match self.name_width {
+            0 => 0,
+            width => width + 1,
+        }
+    }
+
+    /// More lines from a source, as they arrived
+    pub fn push(&mut self, source: usize, lines: Vec<DisplayLine>, now: Instant) {
+        let label_width = self.label_width();
+        let src = &mut self.sources[source];
+        self.waiting += lines.len();
+        for mut line in lines {
+            if label_width > 0 {
+                let pad = label_width - src.name.width();
+                line.spans.splice(
+                    0..0,
+                    [
+                        Span::new(src.name.clone(), SpanLabel::Source),
+                        Span::new(" ".repeat(pad), SpanLabel::Noise),
+                    ],
+                );
+            }
+            // the rest of a record whose start was pushed already
+            match src.chunks.back_mut() {
+                Some(chunk) if chunk.record == line.record => chunk.lines.push(line),
+                _ => {
+                    src.last_ts = line.ts.or(src.last_ts);
+                    src.chunks.push_back(Chunk {
+                        record: line.record,
+                        ts: src.last_ts,
+                        lines: vec![line],
+                        arrived: now,
+                    });
+                }
+            }
+        }
+    }
+
+    /// There's nothing more to come from a source
+    pub fn done(&mut self, source: usize) {
+        self.sources[source].done = true;
+    }
+
+    /// Whether there's nothing more to come from any source, or to drain
+    pub fn is_done(&self) -> bool {
+        self.sources.iter().all(|src| src.done && src.chunks.is_empty())
+    }
+
+    /// When the longest waiting lines have to go out, if any are waiting
+    pub fn deadline(&self) -> Option<Instant> {
+        self.sources
+            .iter()
+            .filter_map(|src| src.chunks.front())
+            .map(|chunk| chunk.arrived + REORDER_WINDOW)
+            .min()
+    }
+
+    /// The lines that can go out now, in order
+    pub fn drain(&mut self, now: Instant) -> Vec<DisplayLine> {
+        self.take(now, false)
+    }
+
+    /// Merge each source's lines anew, e.g. rewrapped, in place of what's
+    /// gone before and anything that was waiting
+    pub fn replace(
+        &mut self,
+        lines: Vec<Vec<DisplayLine>>,
+        now: Instant,
+    ) -> Vec<DisplayLine> {
+        for src in &mut self.sources {
+            src.chunks.clear();
+            src.last_ts = None;
+        }
+        self.waiting = 0;
+        for (source, lines) in lines.into_iter().enumerate() {
+            self.push(source, lines, now);
+        }
+        // all of it's there, so there's no waiting on anything
+        self.take(now, true)
+    }
+
+    /// The lines that can go out, or if all, everything
+    fn take(&mut self, now: Instant, all: bool) -> Vec<DisplayLine> {
+        let mut out = vec![];
+        loop {
+            let earliest = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(i, src)| Some((src.chunks.front()?.ts, i)))
+                .min();
+            let Some((_, i)) = earliest else {
+                break;
+            };
+            // nothing earlier can turn up from a source that's ahead already
+            let caught_up =
+                self.sources.iter().all(|src| src.done || !src.chunks.is_empty());
+            let overdue = self.deadline().is_some_and(|deadline| deadline <= now);
+            if !(all || caught_up || overdue || self.waiting > MAX_WAITING_LINES) {
+                break;
+            }
+            let chunk = self.sources[i].chunks.pop_front().unwrap();
+            self.waiting -= chunk.lines.len();
+            out.extend(chunk.lines);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(lln: usize, record: usize, ts: Option<u32>, text: &str) -> DisplayLine {
+        DisplayLine {
+            lln,
+            record,
+            ll: None,
+            ts: ts.map(|s| format!("2024-03-01T14:00:{s:02}Z").parse().unwrap()),
+            spans: vec![Span::new(text.to_string(), SpanLabel::Text)],
+        }
+    }
+
+    fn texts(lines: &[DisplayLine]) -> Vec<String> {
+        lines.iter().map(|l| l.spans.iter().map(|s| s.text.as_str()).collect()).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let names = ["gw".to_string(), "risk".to_string()];
+        let mut merge = Merge::new(&names);
+        assert_eq!(merge.label_width(), 5);
+        let t0 = Instant::now();
+        merge.push(
+            0,
+            vec![
+                line(0, 0, Some(1), "a"),
+                line(1, 0, None, " a cont"),
+                line(2, 2, Some(5), "b"),
+            ],
+            t0,
+        );
+        // risk might yet have something earlier
+        assert!(merge.drain(t0).is_empty());
+        assert_eq!(merge.deadline(), Some(t0 + REORDER_WINDOW));
+        merge.push(1, vec![line(0, 0, Some(3), "x"), line(1, 1, Some(5), "y")], t0);
+        // now they go out in order until gw runs dry; ties go to the source
+        // listed first
+        assert_eq!(
+            texts(&merge.drain(t0)),
+            vec!["gw   a", "gw    a cont", "risk x", "gw   b"]
+        );
+        // the rest of a record goes with it, even once it's waiting, and a
+        // record without a timestamp goes where the one before it did
+        merge.push(1, vec![line(2, 1, None, " y cont"), line(3, 3, None, "z")], t0);
+        assert!(merge.drain(t0).is_empty());
+        let t1 = t0 + REORDER_WINDOW;
+        assert_eq!(texts(&merge.drain(t1)), vec!["risk y", "risk  y cont", "risk z"]);
+        // a source that's done isn't waited for
+        merge.done(0);
+        assert!(!merge.is_done());
+        merge.push(1, vec![line(4, 4, Some(9), "w")], t1);
+        assert_eq!(texts(&merge.drain(t1)), vec!["risk w"]);
+        merge.done(1);
+        assert!(merge.is_done());
+    }
+
+    #[test]
+    fn test_replace() {
+        let names = ["one".to_string()];
+        let mut merge = Merge::new(&names);
+        // just the one isn't labelled, and never waits
+        assert_eq!(merge.label_width(), 0);
+        let t0 = Instant::now();
+        merge.push(0, vec![line(0, 0, Some(2), "a")], t0);
+        assert_eq!(texts(&merge.drain(t0)), vec!["a"]);
+        let names = ["a".to_string(), "b".to_string()];
+        let mut merge = Merge::new(&names);
+        merge.push(0, vec![line(0, 0, Some(2), "waiting")], t0);
+        let lines = merge.replace(
+            vec![
+                vec![line(0, 0, Some(2), "a1"), line(1, 1, Some(4), "a2")],
+                vec![line(0, 0, Some(3), "b1")],
+            ],
+            t0,
+        );
+        assert_eq!(texts(&lines), vec!["a a1", "b b1", "a a2"]);
+        assert!(merge.drain(t0).is_empty() && !merge.is_done());
+    }
+}
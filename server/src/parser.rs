@@ -1,20 +1,30 @@
-use anyhow::{anyhow, bail, Result};
-use chrono::{DateTime, FixedOffset, Utc};
+use crate::{
+    ansi::{self, Style},
+    config::{Format, JsonFormat, Logset, RegexFormat},
+    query::Query,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDateTime, Utc};
+use log::Level;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::multispace1,
-    combinator::{consumed, map},
-    sequence::tuple,
+    bytes::complete::{escaped, is_not, tag, take_until, take_while, take_while1},
+    character::complete::{anychar, char, digit1, multispace0, multispace1},
+    combinator::{all_consuming, consumed, map, map_res, opt, recognize, verify},
+    multi::{many0, many1},
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
 };
 use regex::Regex;
 use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 pub struct DisplayLine {
     pub lln: usize,      // logical line number
+    pub record: usize,   // lln of the first line of the record it's part of
     pub ll: Option<i32>, // log level
     pub ts: Option<DateTime<Utc>>,
     pub spans: Vec<Span>,
@@ -24,57 +34,62 @@ pub struct DisplayLine {
 pub struct Span {
     pub text: String,
     pub label: SpanLabel,
+    #[serde(skip_serializing_if = "Style::is_plain")]
+    pub style: Style,
 }
 
 impl Span {
+    pub fn new(text: String, label: SpanLabel) -> Self {
+        Span { text, label, style: Style::default() }
+    }
+
     pub fn noise(text: String) -> Self {
-        Span { text, label: SpanLabel::Noise }
+        Span::new(text, SpanLabel::Noise)
     }
 
     pub fn timestamp(text: String) -> Self {
-        Span { text, label: SpanLabel::Timestamp }
+        Span::new(text, SpanLabel::Timestamp)
     }
 
     pub fn level(text: String) -> Self {
-        Span { text, label: SpanLabel::Level }
+        Span::new(text, SpanLabel::Level)
     }
 
     pub fn target(text: String) -> Self {
-        Span { text, label: SpanLabel::Target }
+        Span::new(text, SpanLabel::Target)
     }
 
     pub fn text(text: String) -> Self {
-        Span { text, label: SpanLabel::Text }
+        Span::new(text, SpanLabel::Text)
     }
 
     pub fn text_match(text: String) -> Self {
-        Span { text, label: SpanLabel::TextMatch }
+        Span::new(text, SpanLabel::TextMatch)
     }
 
-    pub fn split_at(&self, width: usize) -> Result<(Span, Span)> {
-        let glyphs = self.text.graphemes(true).collect::<Vec<&str>>();
-        if width > glyphs.len() {
-            return Ok((
-                Span { text: "".to_string(), label: self.label },
-                Span { text: self.text.clone(), label: self.label },
-            ));
-        }
-        let (l, r) = glyphs.split_at(width);
-        let l = l.concat();
-        let r = r.concat();
-        if UnicodeWidthStr::width(l.as_str()) > width {
-            bail!("impossible to break span to width {width}");
-        }
-        Ok((Span { text: l, label: self.label }, Span { text: r, label: self.label }))
+    pub fn field_key(text: String) -> Self {
+        Span::new(text, SpanLabel::FieldKey)
     }
 
-    pub fn split_soft_once(&self) -> Option<(Span, Span, Span)> {
-        let (l, r) = self.text.split_once(" ")?;
-        Some((
-            Span { text: l.to_string(), label: self.label },
-            Span { text: " ".to_string(), label: self.label },
-            Span { text: r.to_string(), label: self.label },
-        ))
+    pub fn field_value(text: String) -> Self {
+        Span::new(text, SpanLabel::FieldValue)
+    }
+
+    /// Split off as much of the start of the span as fits in width
+    /// columns.  A wide grapheme that would straddle the edge goes in the
+    /// right half, so the left may come up a column short.
+    pub fn split_at(&self, width: usize) -> (Span, Span) {
+        let mut len = 0;
+        let mut cum_width = 0;
+        for g in self.text.graphemes(true) {
+            cum_width += grapheme_width(g);
+            if cum_width > width {
+                break;
+            }
+            len += g.len();
+        }
+        let (l, r) = self.text.split_at(len);
+        (Span { text: l.to_string(), ..*self }, Span { text: r.to_string(), ..*self })
     }
 }
 
@@ -87,94 +102,773 @@ pub enum SpanLabel {
     Target,
     Text,
     TextMatch,
+    /// The name of a structured field, when the line says it
+    FieldKey,
+    /// A field of the log line beyond the usual ones, e.g. a thread id
+    FieldValue,
+    /// Which logset the line is from, in a merged view
+    Source,
+}
+
+/// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+
+/// How many columns a grapheme takes up.  Emoji sequences, e.g. flags or
+/// ZWJ sequences, are drawn as one glyph however many there are in them,
+/// so no grapheme is more than 2 wide.  Some, e.g. a lone zero width space,
+/// take up none at all.
+fn grapheme_width(g: &str) -> usize {
+    g.width().min(2)
+}
+
+/// How many columns text takes up, see grapheme_width
+fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// How to wrap lines into rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wrap {
+    pub cols: usize,
+    /// Indent rows after the first so that they line up under the message,
+    /// rather than under the timestamp
+    pub hanging_indent: bool,
+}
+
+#[cfg(test)]
+impl Wrap {
+    pub fn cols(cols: usize) -> Self {
+        Wrap { cols, hanging_indent: false }
+    }
 }
 
 pub struct DisplayLinesBuilder {
     lln: usize,
+    record: usize,
     ll: Option<i32>,
     ts: Option<DateTime<Utc>>,
+    /// The whole line, before wrapping
     spans: Vec<Span>,
-    cum_width: usize,
-    lines: Vec<DisplayLine>,
     cols: usize,
+    /// Columns to indent rows after the first by
+    indent: usize,
 }
 
 impl DisplayLinesBuilder {
     pub fn new(lln: usize, cols: usize) -> Self {
         DisplayLinesBuilder {
             lln,
+            record: lln,
             ll: None,
             ts: None,
             spans: vec![],
-            cum_width: 0,
-            lines: vec![],
-            cols,
+            cols: cols.max(1),
+            indent: 0,
         }
     }
 
-    pub fn build(mut self) -> Vec<DisplayLine> {
-        if !self.spans.is_empty() {
-            self.push_line();
+    pub fn push_span(&mut self, span: Span) {
+        if !span.text.is_empty() {
+            self.spans.push(span);
         }
-        self.lines
     }
 
-    fn push_line(&mut self) {
-        let spans = std::mem::replace(&mut self.spans, vec![]);
-        self.cum_width = 0;
-        self.lines.push(DisplayLine { lln: self.lln, ll: self.ll, ts: self.ts, spans });
-    }
-
-    pub fn push_span(&mut self, span: Span) -> Result<()> {
-        if span.text.is_empty() {
-            return Ok(());
-        }
-        let span_width = span.text.width();
-        if self.cum_width + span_width > self.cols {
-            // the span too wide, try a soft break
-            match span.split_soft_once() {
-                // CR alee: this will soft break way more often than needed
-                Some((l, w, r)) => {
-                    self.push_span(l)?;
-                    self.push_span(w)?;
-                    self.push_span(r)?;
+    /// Wrap what's been pushed into rows, putting as many words on each as
+    /// fit.  A word too wide for a row of its own is broken wherever, and
+    /// whitespace that doesn't fit at the end of a row is dropped.  Rows
+    /// are only ever wider than cols if there's one grapheme wider than
+    /// that on them.
+    pub fn build(self) -> Vec<DisplayLine> {
+        // an indent that leaves hardly any room isn't worth it, and one that
+        // leaves no room for a wide glyph would be wider than cols with it
+        let room = self.cols.saturating_sub(self.indent);
+        let indent = if room >= self.indent && room >= 2 { self.indent } else { 0 };
+        let mut rows = vec![];
+        let mut row = vec![];
+        let mut col = 0;
+        // where the row's own spans start, after any indent
+        let mut start = 0;
+        let mut end_row = |row: &mut Vec<Span>, col: &mut usize, start: &mut usize| {
+            rows.push(std::mem::take(row));
+            if indent > 0 {
+                row.push(Span::noise(" ".repeat(indent)));
+            }
+            *col = indent;
+            *start = indent;
+        };
+        for word in words(&self.spans) {
+            let (word, space) = split_space(word);
+            let width = word
+                .iter()
+                .map(|span| display_width(&expand_tabs(span.clone(), col).text))
+                .sum::<usize>();
+            if col > start && col + width > self.cols {
+                end_row(&mut row, &mut col, &mut start);
+            }
+            for span in word {
+                let mut span = expand_tabs(span, col);
+                loop {
+                    let width = display_width(&span.text);
+                    if col + width <= self.cols {
+                        col += width;
+                        push_merged(&mut row, span);
+                        break;
+                    }
+                    let (mut l, mut r) = span.split_at(self.cols.saturating_sub(col));
+                    if l.text.is_empty() && col == start {
+                        // too wide for a row of its own, so it gets one anyway
+                        let len = r.text.graphemes(true).next().map_or(0, str::len);
+                        l.text = r.text.drain(..len).collect();
+                    }
+                    if !l.text.is_empty() {
+                        push_merged(&mut row, l);
+                    }
+                    end_row(&mut row, &mut col, &mut start);
+                    span = r;
+                    if span.text.is_empty() {
+                        break;
+                    }
                 }
-                None => {
-                    // no soft break available
-                    if span_width > self.cols {
-                        // absolutely too wide, hardbreak
-                        let (l, r) = span.split_at(self.cols - self.cum_width)?;
-                        if !l.text.is_empty() {
-                            self.spans.push(l);
-                        }
-                        self.push_line();
-                        self.push_span(r)?;
-                    } else {
-                        self.push_line();
-                        self.push_span(span)?;
+            }
+            for span in space {
+                let span = expand_tabs(span, col);
+                let room = self.cols.saturating_sub(col);
+                let mut len = 0;
+                let mut width = 0;
+                for g in span.text.graphemes(true) {
+                    if width + grapheme_width(g) > room {
+                        break;
                     }
+                    len += g.len();
+                    width += grapheme_width(g);
+                }
+                if len > 0 {
+                    col += width;
+                    push_merged(
+                        &mut row,
+                        Span { text: span.text[..len].to_string(), ..span },
+                    );
                 }
             }
+        }
+        if row.len() > usize::from(start > 0) {
+            rows.push(row);
+        }
+        rows.into_iter()
+            .map(|spans| DisplayLine {
+                lln: self.lln,
+                record: self.record,
+                ll: self.ll,
+                ts: self.ts,
+                spans,
+            })
+            .collect()
+    }
+}
+
+/// Split spans up into words, i.e. the runs of text between the places the
+/// Unicode line breaking algorithm allows a break, each with whatever
+/// whitespace comes after it
+fn words(spans: &[Span]) -> Vec<Vec<Span>> {
+    let text = spans.iter().map(|span| span.text.as_str()).collect::<String>();
+    let mut breaks = unicode_linebreak::linebreaks(&text).map(|(at, _)| at).peekable();
+    let mut words = vec![];
+    let mut word = vec![];
+    let mut offset = 0;
+    for span in spans {
+        let end = offset + span.text.len();
+        let mut start = offset;
+        while let Some(at) = breaks.next_if(|at| *at <= end) {
+            if at > start {
+                word.push(Span {
+                    text: span.text[start - offset..at - offset].to_string(),
+                    ..*span
+                });
+                start = at;
+            }
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        if start < end {
+            word.push(Span { text: span.text[start - offset..].to_string(), ..*span });
+        }
+        offset = end;
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Push span onto row, as part of the last span if it looks the same
+fn push_merged(row: &mut Vec<Span>, span: Span) {
+    match row.last_mut() {
+        Some(last) if last.label == span.label && last.style == span.style => {
+            last.text.push_str(&span.text);
+        }
+        _ => row.push(span),
+    }
+}
+
+/// Split the whitespace off the end of a word
+fn split_space(mut word: Vec<Span>) -> (Vec<Span>, Vec<Span>) {
+    let mut space = vec![];
+    while let Some(mut span) = word.pop() {
+        let len = span.text.trim_end().len();
+        if len < span.text.len() {
+            space.push(Span { text: span.text.split_off(len), ..span });
+        }
+        if !span.text.is_empty() {
+            word.push(span);
+            break;
+        }
+    }
+    space.reverse();
+    (word, space)
+}
+
+/// Turn the tabs in span into spaces up to the next tab stop, for span
+/// starting at column col
+fn expand_tabs(span: Span, mut col: usize) -> Span {
+    if !span.text.contains('\t') {
+        return span;
+    }
+    let mut text = String::with_capacity(span.text.len());
+    for g in span.text.graphemes(true) {
+        if g == "\t" {
+            let n = TAB_WIDTH - col % TAB_WIDTH;
+            text.extend(std::iter::repeat_n(' ', n));
+            col += n;
         } else {
-            self.cum_width += span_width;
-            self.spans.push(span);
-            if self.cum_width == self.cols {
-                self.push_line();
+            text.push_str(g);
+            col += grapheme_width(g);
+        }
+    }
+    Span { text, ..span }
+}
+
+/// A log line split into labelled spans, before filtering and wrapping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedLine {
+    pub ll: Option<i32>,
+    pub ts: Option<DateTime<Utc>>,
+    /// Spans before the message, e.g. the timestamp, level and target
+    pub header: Vec<Span>,
+    /// The message, which is what the regex filter matches against
+    pub text: String,
+    /// Spans after the message, e.g. structured fields
+    pub trailer: Vec<Span>,
+    /// Fields beyond the timestamp, level, target and message, by name,
+    /// which the regex filter matches against too
+    pub fields: Vec<(String, String)>,
+    /// Where the style set by ANSI escapes changes, as byte offsets into the
+    /// header, text and trailer laid end to end, see ansi::Stripper::styles
+    pub styles: Vec<(usize, Style)>,
+}
+
+/// Which lines to show
+#[derive(Debug, Clone, Default)]
+pub struct LineFilter {
+    pub regex: Option<Regex>,
+    /// Only records the query matches
+    pub query: Option<Query>,
+    /// Only lines timestamped at or after this
+    pub since: Option<DateTime<Utc>>,
+    /// Only lines timestamped before this
+    pub until: Option<DateTime<Utc>>,
+    /// Only lines at least this severe
+    pub min_level: Option<Level>,
+}
+
+impl LineFilter {
+    /// Whether the regex, if any, matches a line's message or fields
+    pub fn matches(&self, parsed: &ParsedLine) -> bool {
+        match &self.regex {
+            Some(regex) => {
+                regex.is_match(&parsed.text)
+                    || parsed.fields.iter().any(|(_, value)| regex.is_match(value))
             }
+            None => true,
+        }
+    }
+
+    /// Whether the query, if any, matches a record
+    pub fn query_matches(
+        &self,
+        ts: Option<DateTime<Utc>>,
+        ll: Option<i32>,
+        lines: &[ParsedLine],
+    ) -> bool {
+        self.query.as_ref().is_none_or(|query| query.matches(ts, ll, lines))
+    }
+
+    /// What to highlight as matches: whatever the regex or the query look
+    /// for
+    pub fn highlight(&self) -> Option<Regex> {
+        match (&self.regex, self.query.as_ref().and_then(Query::highlight)) {
+            (Some(regex), Some(query)) => {
+                Regex::new(&format!("(?:{})|(?:{})", regex.as_str(), query.as_str())).ok()
+            }
+            (regex, query) => regex.clone().or_else(|| query.cloned()),
+        }
+    }
+
+    /// Whether a line with timestamp ts is within since/until.  A line with
+    /// no timestamp at all only passes if there are no bounds.
+    pub fn in_window(&self, ts: Option<DateTime<Utc>>) -> bool {
+        match ts {
+            Some(ts) => {
+                self.since.is_none_or(|since| ts >= since)
+                    && self.until.is_none_or(|until| ts < until)
+            }
+            None => self.since.is_none() && self.until.is_none(),
+        }
+    }
+
+    /// Whether a line at level ll (0 for ERROR through 4 for TRACE) is at
+    /// least min_level.  A line with no level only passes if there's no
+    /// min_level.
+    pub fn at_level(&self, ll: Option<i32>) -> bool {
+        match (self.min_level, ll) {
+            (Some(min_level), Some(ll)) => ll <= level_ll(min_level),
+            (Some(_), None) => false,
+            (None, _) => true,
         }
-        Ok(())
     }
 }
 
-// CR alee: what would be the syntax for user-configured parses?
-pub fn parse_log_line(
-    lln: usize,
-    cols: usize,
-    line: &str,
-    filter: Option<&Regex>,
-) -> Result<Option<Vec<DisplayLine>>> {
+impl ParsedLine {
+    /// A line that didn't match the format, all message
+    pub fn plain(line: &str) -> Self {
+        ParsedLine {
+            ll: None,
+            ts: None,
+            header: vec![],
+            text: line.to_string(),
+            trailer: vec![],
+            fields: vec![],
+            styles: vec![],
+        }
+    }
+
+    /// Put back the styles of the escapes that were stripped from line
+    /// before it was parsed into this, and take out any the parser itself
+    /// let through, e.g. JSON-escaped ones
+    fn restyle(&mut self, line: &str, styles: Vec<(usize, Style)>) {
+        let pieces = || {
+            let header = self.header.iter().map(|span| span.text.as_str());
+            let trailer = self.trailer.iter().map(|span| span.text.as_str());
+            header.chain([self.text.as_str()]).chain(trailer)
+        };
+        let mut rest = line;
+        let in_order = pieces().all(|piece| match rest.strip_prefix(piece) {
+            Some(r) => {
+                rest = r;
+                true
+            }
+            None => false,
+        });
+        if in_order {
+            self.styles = styles;
+            return;
+        }
+        // the parser rearranged the line, so there's no saying where the
+        // styles go any more
+        if !pieces().any(|piece| piece.contains(ansi::ESC)) {
+            return;
+        }
+        let mut stripper = ansi::Stripper::default();
+        for span in &mut self.header {
+            span.text = stripper.strip(&span.text);
+        }
+        self.text = stripper.strip(&self.text);
+        for span in &mut self.trailer {
+            span.text = stripper.strip(&span.text);
+        }
+        self.styles = stripper.styles();
+        for (_, value) in &mut self.fields {
+            *value = ansi::strip(value).0.into_owned();
+        }
+    }
+}
+
+/// Splits lines up according to a logset's format
+#[derive(Debug, Clone)]
+pub enum LineParser {
+    EnvLogger,
+    Regex(RegexParser),
+    Json(JsonParser),
+    Logfmt,
+    Syslog(SyslogParser),
+    Plain,
+}
+
+impl LineParser {
+    pub fn new(format: &Format) -> Result<Self> {
+        Ok(match format {
+            Format::EnvLogger => LineParser::EnvLogger,
+            Format::Regex(format) => LineParser::Regex(RegexParser::new(format)?),
+            Format::Json(format) => LineParser::Json(JsonParser::new(format)),
+            Format::Logfmt => LineParser::Logfmt,
+            Format::Syslog => LineParser::Syslog(SyslogParser { reference: Utc::now() }),
+            Format::Plain => LineParser::Plain,
+        })
+    }
+
+    /// The parser for a logset, detecting its format if it isn't configured,
+    /// and resolving year-less timestamps against the mtime of its current
+    /// file
+    pub fn for_logset(logset: &Logset) -> Result<Self> {
+        let mut parser = match &logset.format {
+            Some(format) => Self::new(format)?,
+            None => Self::new(&detect_format(logset)?)?,
+        };
+        parser.set_reference(std::fs::metadata(logset.current())?.modified()?.into());
+        Ok(parser)
+    }
+
+    /// Resolve year-less timestamps against this from now on, e.g. the
+    /// current file's mtime as of the latest read
+    pub fn set_reference(&mut self, reference: DateTime<Utc>) {
+        if let LineParser::Syslog(syslog) = self {
+            syslog.reference = reference;
+        }
+    }
+
+    /// Parse a line, with any ANSI escapes in it taken out and turned into
+    /// styles
+    pub fn parse(&self, line: &str) -> Result<ParsedLine> {
+        let (line, styles) = ansi::strip(line);
+        let line = line.as_ref();
+        let mut parsed = match self {
+            LineParser::EnvLogger => parse_env_logger(line)?,
+            LineParser::Regex(parser) => parser.parse(line),
+            LineParser::Json(parser) => parser.parse(line),
+            LineParser::Logfmt => parse_logfmt(line),
+            LineParser::Syslog(parser) => parser.parse(line),
+            LineParser::Plain => ParsedLine::plain(line),
+        };
+        parsed.restyle(line, styles);
+        Ok(parsed)
+    }
+
+    /// Whether a line starts a new record, rather than continuing the one
+    /// before, i.e. whether it was recognised as being in the format at all
+    pub fn starts_record(&self, line: &str, parsed: &ParsedLine) -> bool {
+        matches!(self, LineParser::Plain)
+            || parsed.ts.is_some()
+            || parsed.ll.is_some()
+            || !parsed.header.is_empty()
+            || !parsed.trailer.is_empty()
+            || parsed.text != ansi::strip(line).0
+    }
+
+    /// Just the timestamp of a log line, if it has one
+    pub fn timestamp(&self, line: &str) -> Option<DateTime<Utc>> {
+        self.parse(line).ok()?.ts
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegexParser {
+    regex: Regex,
+    ts_format: Option<String>,
+    levels: HashMap<String, Level>,
+}
+
+impl RegexParser {
+    pub fn new(format: &RegexFormat) -> Result<Self> {
+        Ok(RegexParser {
+            regex: Regex::new(&format.regex)?,
+            ts_format: format.ts_format.clone(),
+            levels: format.levels.clone(),
+        })
+    }
+
+    /// Everything before the `msg` capture is labelled by capture name, the
+    /// gaps between captures being noise; the message runs from the start
+    /// of `msg` (or the end of the match, without one) to the end of the
+    /// line.
+    fn parse(&self, line: &str) -> ParsedLine {
+        let Some(caps) = self.regex.captures(line) else {
+            return ParsedLine::plain(line);
+        };
+        let end = match caps.name("msg") {
+            Some(m) => m.start(),
+            None => caps.get(0).unwrap().end(),
+        };
+        let mut groups = self
+            .regex
+            .capture_names()
+            .flatten()
+            .filter(|name| *name != "msg")
+            .filter_map(|name| caps.name(name).map(|m| (name, m)))
+            .filter(|(_, m)| m.end() <= end)
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|(_, m)| m.start());
+        let mut parsed = ParsedLine::plain(&line[end..]);
+        let mut pos = 0;
+        for (name, m) in groups {
+            if m.start() < pos {
+                // nested in or overlapping the one before
+                continue;
+            }
+            parsed.header.push(Span::noise(line[pos..m.start()].to_string()));
+            let text = m.as_str().to_string();
+            parsed.header.push(match name {
+                "ts" => {
+                    parsed.ts = parse_ts(m.as_str(), self.ts_format.as_deref());
+                    Span::timestamp(text)
+                }
+                "level" => {
+                    parsed.ll = match self.levels.get(m.as_str()) {
+                        Some(level) => Some(level_ll(*level)),
+                        None => parse_level(m.as_str()),
+                    };
+                    Span::level(text)
+                }
+                "target" => Span::target(text),
+                _ => {
+                    parsed.fields.push((name.to_string(), text.clone()));
+                    Span::field_value(text)
+                }
+            });
+            pos = m.end();
+        }
+        parsed.header.push(Span::noise(line[pos..end].to_string()));
+        parsed.header.retain(|span| !span.text.is_empty());
+        parsed
+    }
+}
+
+/// How much of a logset to look at when detecting its format
+const SAMPLE_LINES: usize = 200;
+const SAMPLE_BYTES: usize = 64 * 1024;
+
+/// Guess a logset's format from the first lines of its newest file that has
+/// any
+pub fn detect_format(logset: &Logset) -> Result<Format> {
+    use std::io::Read;
+    let mut buf = vec![];
+    for path in [logset.current()].into_iter().chain(logset.archives()?.into_iter().rev())
+    {
+        let f = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            // rotated away in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        f.take(SAMPLE_BYTES as u64).read_to_end(&mut buf)?;
+        if !buf.is_empty() {
+            break;
+        }
+    }
+    let sample = String::from_utf8_lossy(&buf);
+    let mut lines = sample.lines().collect::<Vec<_>>();
+    if buf.len() == SAMPLE_BYTES {
+        // probably cut off partway through
+        lines.pop();
+    }
+    lines.truncate(SAMPLE_LINES);
+    Ok(detect(&lines))
+}
+
+/// The format that makes sense of the most lines, or plain if none make
+/// sense of at least one in ten (continuation lines, e.g. of backtraces, are
+/// expected to make sense to none of them)
+pub fn detect(lines: &[&str]) -> Format {
+    let candidates = [
+        Format::EnvLogger,
+        Format::Json(JsonFormat::default()),
+        Format::Logfmt,
+        Format::Syslog,
+    ];
+    let lines = lines.iter().filter(|line| !line.trim().is_empty()).collect::<Vec<_>>();
+    let mut best = (0, Format::Plain);
+    for format in candidates {
+        let Ok(parser) = LineParser::new(&format) else {
+            continue;
+        };
+        let score = lines
+            .iter()
+            .filter(|line| {
+                parser.parse(line).is_ok_and(|parsed| parsed != ParsedLine::plain(line))
+            })
+            .count();
+        if score > best.0 {
+            best = (score, format);
+        }
+    }
+    if best.0 > 0 && best.0 * 10 >= lines.len() {
+        best.1
+    } else {
+        Format::Plain
+    }
+}
+
+/// Keys tried in turn when the format doesn't say, covering tracing-subscriber,
+/// bunyan, pino and the like
+const JSON_TS_KEYS: &[&str] = &["timestamp", "time", "ts", "@timestamp"];
+const JSON_LEVEL_KEYS: &[&str] = &["level", "lvl", "severity"];
+const JSON_TARGET_KEYS: &[&str] = &["target", "logger", "name"];
+const JSON_MSG_KEYS: &[&str] = &["message", "msg", "fields.message"];
+
+/// A JSON object per line.  Nested objects are flattened, with dotted keys.
+#[derive(Debug, Clone)]
+pub struct JsonParser {
+    ts_keys: Vec<String>,
+    level_keys: Vec<String>,
+    target_keys: Vec<String>,
+    msg_keys: Vec<String>,
+}
+
+impl JsonParser {
+    pub fn new(format: &JsonFormat) -> Self {
+        let keys = |key: &Option<String>, defaults: &[&str]| match key {
+            Some(key) => vec![key.clone()],
+            None => defaults.iter().map(|key| key.to_string()).collect(),
+        };
+        JsonParser {
+            ts_keys: keys(&format.ts_key, JSON_TS_KEYS),
+            level_keys: keys(&format.level_key, JSON_LEVEL_KEYS),
+            target_keys: keys(&format.target_key, JSON_TARGET_KEYS),
+            msg_keys: keys(&format.msg_key, JSON_MSG_KEYS),
+        }
+    }
+
+    /// Laid out as `ts level target message key=value...`
+    fn parse(&self, line: &str) -> ParsedLine {
+        let Ok(serde_json::Value::Object(obj)) = serde_json::from_str(line) else {
+            return ParsedLine::plain(line);
+        };
+        let mut fields = vec![];
+        flatten_json(String::new(), obj, &mut fields);
+        let mut take = |keys: &[String]| {
+            keys.iter().find_map(|key| {
+                let i = fields.iter().position(|(k, _)| k == key)?;
+                Some(fields.remove(i).1)
+            })
+        };
+        let ts = take(&self.ts_keys);
+        let level = take(&self.level_keys);
+        let target = take(&self.target_keys);
+        let msg = take(&self.msg_keys);
+        let mut parsed =
+            ParsedLine::plain(&msg.as_ref().map(json_text).unwrap_or_default());
+        if let Some(ts) = ts {
+            parsed.ts = match &ts {
+                serde_json::Value::String(s) => parse_ts(s, None),
+                serde_json::Value::Number(n) => n.as_f64().and_then(epoch_ts),
+                _ => None,
+            };
+            parsed.header.push(Span::timestamp(json_text(&ts)));
+        }
+        if let Some(level) = level {
+            parsed.ll = match &level {
+                serde_json::Value::String(s) => parse_level(s),
+                serde_json::Value::Number(n) => n.as_u64().map(bunyan_level),
+                _ => None,
+            };
+            parsed.header.push(Span::level(json_text(&level)));
+        }
+        if let Some(target) = target {
+            parsed.header.push(Span::target(json_text(&target)));
+        }
+        // space everything out, with a space before the message
+        parsed.header = parsed
+            .header
+            .into_iter()
+            .flat_map(|span| [span, Span::noise(" ".to_string())])
+            .collect();
+        for (key, value) in fields {
+            let value = json_text(&value);
+            parsed.trailer.push(Span::noise(" ".to_string()));
+            parsed.trailer.push(Span::field_key(key.clone()));
+            parsed.trailer.push(Span::noise("=".to_string()));
+            parsed.trailer.push(Span::field_value(value.clone()));
+            parsed.fields.push((key, value));
+        }
+        parsed
+    }
+}
+
+fn flatten_json(
+    prefix: String,
+    obj: serde_json::Map<String, serde_json::Value>,
+    out: &mut Vec<(String, serde_json::Value)>,
+) {
+    for (key, value) in obj {
+        let key = if prefix.is_empty() { key } else { format!("{prefix}.{key}") };
+        match value {
+            serde_json::Value::Object(obj) => flatten_json(key, obj, out),
+            value => out.push((key, value)),
+        }
+    }
+}
+
+/// Strings as they are, anything else as JSON
+fn json_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Seconds since the epoch, or milliseconds if that would be far in the
+/// future
+fn epoch_ts(t: f64) -> Option<DateTime<Utc>> {
+    let millis = if t < 1e11 { t * 1000. } else { t };
+    DateTime::from_timestamp_millis(millis as i64)
+}
+
+/// Bunyan/pino numeric levels: 10 trace, 20 debug, 30 info, 40 warn, 50 error,
+/// 60 fatal
+fn bunyan_level(n: u64) -> i32 {
+    match n {
+        50.. => 0,
+        40..=49 => 1,
+        30..=39 => 2,
+        20..=29 => 3,
+        _ => 4,
+    }
+}
+
+/// Parse a timestamp with a strftime format, taking it to be UTC if the
+/// format has no offset, or as RFC 3339 without one
+fn parse_ts(s: &str, format: Option<&str>) -> Option<DateTime<Utc>> {
+    match format {
+        Some(format) => match DateTime::parse_from_str(s, format) {
+            Ok(dt) => Some(dt.with_timezone(&Utc)),
+            Err(_) => {
+                NaiveDateTime::parse_from_str(s, format).ok().map(|dt| dt.and_utc())
+            }
+        },
+        None => DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)),
+    }
+}
+
+/// The ll of a level name like "WARN" or "warning", 0 for ERROR through 4
+/// for TRACE
+pub fn parse_level(s: &str) -> Option<i32> {
+    let level = match s.to_ascii_lowercase().as_str() {
+        "warning" => Level::Warn,
+        "fatal" | "critical" | "crit" | "err" | "eror" => Level::Error,
+        "dbug" => Level::Debug,
+        s => s.parse().ok()?,
+    };
+    Some(level_ll(level))
+}
+
+/// The ll of a level, 0 for ERROR through 4 for TRACE
+pub fn level_ll(level: Level) -> i32 {
+    // Level counts from 1 for Error
+    level as i32 - 1
+}
+
+/// `[ts LEVEL target] msg`
+fn parse_env_logger(line: &str) -> Result<ParsedLine> {
     let utf8 = |s: &[u8]| -> Result<String> { Ok(std::str::from_utf8(s)?.to_string()) };
-    let mut ret = DisplayLinesBuilder::new(lln, cols);
     let parse_log_level = alt((
         map(tag("ERROR"), |_| 0),
         map(tag("WARN"), |_| 1),
@@ -182,7 +876,7 @@ pub fn parse_log_line(
         map(tag("DEBUG"), |_| 3),
         map(tag("TRACE"), |_| 4),
     ));
-    let rem = match tuple((
+    match tuple((
         consumed(tag("[")),
         consumed(iso8601::parsers::parse_datetime),
         consumed(multispace1),
@@ -195,49 +889,542 @@ pub fn parse_log_line(
         Ok((rem, (lb, ts, w, ll, ww, target, rb))) => {
             let dt: DateTime<FixedOffset> =
                 ts.1.try_into().map_err(|_| anyhow!("ts conv"))?;
-            ret.ts = Some(dt.with_timezone(&Utc));
-            ret.ll = Some(ll.1);
-            ret.push_span(Span::noise(utf8(lb.0)?))?;
-            ret.push_span(Span::timestamp(utf8(ts.0)?))?;
-            ret.push_span(Span::noise(utf8(w.0)?))?;
-            ret.push_span(Span::level(utf8(ll.0)?))?;
-            ret.push_span(Span::noise(utf8(ww.0)?))?;
-            ret.push_span(Span::target(utf8(target.0)?))?;
-            ret.push_span(Span::noise(utf8(rb.0)?))?;
-            std::str::from_utf8(rem)?
-        }
-        _ => line,
+            Ok(ParsedLine {
+                ll: Some(ll.1),
+                ts: Some(dt.with_timezone(&Utc)),
+                header: vec![
+                    Span::noise(utf8(lb.0)?),
+                    Span::timestamp(utf8(ts.0)?),
+                    Span::noise(utf8(w.0)?),
+                    Span::level(utf8(ll.0)?),
+                    Span::noise(utf8(ww.0)?),
+                    Span::target(utf8(target.0)?),
+                    Span::noise(utf8(rb.0)?),
+                ],
+                text: utf8(rem)?,
+                trailer: vec![],
+                fields: vec![],
+                styles: vec![],
+            })
+        }
+        _ => Ok(ParsedLine::plain(line)),
+    }
+}
+
+/// Whitespace, key and (if there's an =) value, with any quotes kept
+type LogfmtPair<'a> = (&'a str, &'a str, Option<&'a str>);
+
+fn logfmt_pairs(line: &str) -> IResult<&str, Vec<LogfmtPair<'_>>> {
+    let key = take_while1(|c: char| c.is_alphanumeric() || "_.-/@:".contains(c));
+    let quoted = recognize(delimited(
+        char('"'),
+        opt(escaped(is_not("\\\""), '\\', anychar)),
+        char('"'),
+    ));
+    let bare = take_while(|c: char| c > ' ' && c != '"');
+    let value = preceded(char('='), alt((quoted, bare)));
+    let (rem, pairs) = all_consuming(pair(
+        many1(tuple((multispace0, key, opt(value)))),
+        multispace0,
+    ))(line)?;
+    Ok((rem, pairs.0))
+}
+
+/// `key=value key2="quoted value"`, with the timestamp, level and message
+/// taken from `ts`/`time`, `level`/`lvl` and `msg`.  A line that isn't all
+/// pairs, with at least one =, isn't logfmt.
+fn parse_logfmt(line: &str) -> ParsedLine {
+    let Ok((_, pairs)) = logfmt_pairs(line) else {
+        return ParsedLine::plain(line);
     };
-    match filter {
-        Some(filter) => {
-            if !filter.is_match(rem) {
-                // short-circuit if the line doesn't match the filter
-                return Ok(None);
+    if pairs.iter().all(|(_, _, value)| value.is_none()) {
+        return ParsedLine::plain(line);
+    }
+    let mut parsed = ParsedLine::plain("");
+    let mut msg = false;
+    for (space, key, value) in pairs {
+        // spans go before the message until it's been seen, after after
+        let spans = if msg { &mut parsed.trailer } else { &mut parsed.header };
+        spans.push(Span::noise(space.to_string()));
+        spans.push(Span::field_key(key.to_string()));
+        let Some(value) = value else {
+            continue;
+        };
+        spans.push(Span::noise("=".to_string()));
+        let unquoted = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(inner) => serde_json::from_str(value).unwrap_or(inner.to_string()),
+            None => value.to_string(),
+        };
+        match key {
+            "ts" | "time" if parsed.ts.is_none() => {
+                parsed.ts = parse_ts(&unquoted, None);
+                spans.push(Span::timestamp(value.to_string()));
             }
-            let mut matches = vec![];
-            for m in filter.find_iter(rem) {
-                matches.push((m.start(), m.end()));
+            "level" | "lvl" if parsed.ll.is_none() => {
+                parsed.ll = parse_level(&unquoted);
+                spans.push(Span::level(value.to_string()));
             }
-            let mut last = 0;
-            for (start, end) in matches {
-                ret.push_span(Span::text(rem[last..start].to_string()))?;
-                ret.push_span(Span::text_match(rem[start..end].to_string()))?;
-                last = end;
+            "msg" if !msg => {
+                msg = true;
+                // the quotes are noise, and what's between them, unescaped
+                // like any other value, the message
+                if value.starts_with('"') {
+                    spans.push(Span::noise("\"".to_string()));
+                    parsed.trailer.push(Span::noise("\"".to_string()));
+                }
+                parsed.text = unquoted;
+            }
+            _ => {
+                spans.push(Span::field_value(value.to_string()));
+                parsed.fields.push((key.to_string(), unquoted));
+            }
+        }
+    }
+    parsed.header.retain(|span| !span.text.is_empty());
+    parsed.trailer.retain(|span| !span.text.is_empty());
+    parsed
+}
+
+/// RFC 5424 and RFC 3164 syslog lines, with or without the PRI (rsyslog
+/// leaves it out of files by default)
+#[derive(Debug, Clone)]
+pub struct SyslogParser {
+    /// RFC 3164 timestamps have no year, so take the one that puts them
+    /// closest to before this
+    reference: DateTime<Utc>,
+}
+
+impl SyslogParser {
+    fn parse(&self, line: &str) -> ParsedLine {
+        parse_rfc5424(line)
+            .or_else(|| self.parse_rfc3164(line))
+            .unwrap_or_else(|| ParsedLine::plain(line))
+    }
+
+    /// `<PRI>Mmm dd hh:mm:ss host tag[pid]: msg`, where the timestamp may
+    /// be RFC 3339 instead
+    fn parse_rfc3164(&self, line: &str) -> Option<ParsedLine> {
+        let mut parsed = ParsedLine::plain("");
+        let (rem, pri) = opt(consumed(syslog_pri))(line).ok()?;
+        if let Some((text, pri)) = pri {
+            parsed.ll = Some(syslog_level(pri));
+            parsed.header.push(Span::level(text.to_string()));
+        }
+        let (ts_text, ts) = match rem.get(..15).and_then(|ts| self.bsd_ts(ts)) {
+            Some(ts) => (&rem[..15], Some(ts)),
+            None => {
+                let ts = rem.split(' ').next()?;
+                (ts, Some(parse_ts(ts, None)?))
+            }
+        };
+        parsed.ts = ts;
+        parsed.header.push(Span::timestamp(ts_text.to_string()));
+        let field = || take_while1(|c: char| c > ' ');
+        let tag_name = take_while1(|c: char| c > ' ' && c != '[' && c != ':');
+        let pid = recognize(delimited(char('['), is_not("]"), char(']')));
+        let res: IResult<_, _> = tuple((
+            tag(" "),
+            field(),
+            tag(" "),
+            recognize(pair(tag_name, opt(pid))),
+            alt((tag(": "), tag(":"))),
+        ))(&rem[ts_text.len()..]);
+        let (msg, (sp, host, sp2, tag, colon)) = res.ok()?;
+        parsed.header.push(Span::noise(sp.to_string()));
+        parsed.header.push(Span::field_value(host.to_string()));
+        parsed.fields.push(("host".to_string(), host.to_string()));
+        parsed.header.push(Span::noise(sp2.to_string()));
+        parsed.header.push(Span::target(tag.to_string()));
+        parsed.header.push(Span::noise(colon.to_string()));
+        parsed.text = msg.to_string();
+        Some(parsed)
+    }
+
+    /// `Mmm dd hh:mm:ss`, in the latest year that doesn't put it more than
+    /// a month after the reference time
+    fn bsd_ts(&self, ts: &str) -> Option<DateTime<Utc>> {
+        let year = self.reference.year();
+        (year - 1..=year + 1).rev().find_map(|year| {
+            let ts =
+                NaiveDateTime::parse_from_str(&format!("{year} {ts}"), "%Y %b %e %T")
+                    .ok()?
+                    .and_utc();
+            (ts <= self.reference + Duration::days(30)).then_some(ts)
+        })
+    }
+}
+
+/// `<PRI>`, facility * 8 + severity
+fn syslog_pri(i: &str) -> IResult<&str, u8> {
+    delimited(
+        char('<'),
+        verify(map_res(digit1, str::parse::<u8>), |pri| *pri < 192),
+        char('>'),
+    )(i)
+}
+
+/// The ll of a syslog PRI: emerg through err are errors, notice is info
+fn syslog_level(pri: u8) -> i32 {
+    match pri % 8 {
+        0..=3 => 0,
+        4 => 1,
+        5 | 6 => 2,
+        _ => 3,
+    }
+}
+
+/// `<PRI>1 ts host app procid msgid [sd-id k="v"...] msg`, where any of
+/// ts through msgid may be - for nothing
+fn parse_rfc5424(line: &str) -> Option<ParsedLine> {
+    let field = || take_while1(|c: char| c > ' ');
+    let sp = || consumed(char(' '));
+    let res: IResult<_, _> = tuple((
+        consumed(syslog_pri),
+        tag("1"),
+        sp(),
+        field(),
+        sp(),
+        field(),
+        sp(),
+        field(),
+        sp(),
+        field(),
+        sp(),
+        field(),
+        sp(),
+    ))(line);
+    let (rem, (pri, version, sp1, ts, sp2, host, sp3, app, sp4, procid, sp5, msgid, sp6)) =
+        res.ok()?;
+    let mut parsed = ParsedLine::plain("");
+    parsed.ll = Some(syslog_level(pri.1));
+    parsed.header.push(Span::level(pri.0.to_string()));
+    parsed.header.push(Span::noise(version.to_string()));
+    parsed.header.push(Span::noise(sp1.0.to_string()));
+    parsed.ts = parse_ts(ts, None);
+    parsed.header.push(Span::timestamp(ts.to_string()));
+    parsed.header.push(Span::noise(sp2.0.to_string()));
+    parsed.header.push(Span::field_value(host.to_string()));
+    parsed.fields.push(("host".to_string(), host.to_string()));
+    parsed.header.push(Span::noise(sp3.0.to_string()));
+    parsed.header.push(Span::target(app.to_string()));
+    parsed.header.push(Span::noise(sp4.0.to_string()));
+    parsed.header.push(Span::target(procid.to_string()));
+    parsed.header.push(Span::noise(sp5.0.to_string()));
+    parsed.header.push(Span::field_value(msgid.to_string()));
+    parsed.fields.push(("msgid".to_string(), msgid.to_string()));
+    parsed.header.push(Span::noise(sp6.0.to_string()));
+    // structured data, each element as [ sd-id, then key=value params ]
+    let sd_name = || take_while1(|c: char| c > ' ' && !"=]\"".contains(c));
+    let sd_value = recognize(delimited(
+        char('"'),
+        opt(escaped(is_not("\\\""), '\\', anychar)),
+        char('"'),
+    ));
+    let sd_param = tuple((char(' '), sd_name(), char('='), sd_value));
+    let element = tuple((char('['), sd_name(), many0(sd_param), char(']')));
+    let res: IResult<_, _> = alt((map(tag("-"), |_| vec![]), many1(element)))(rem);
+    let (rem, elements) = res.ok()?;
+    if elements.is_empty() {
+        parsed.header.push(Span::noise("-".to_string()));
+    }
+    for (_, id, params, _) in elements {
+        parsed.header.push(Span::noise("[".to_string()));
+        parsed.header.push(Span::field_key(id.to_string()));
+        for (_, name, _, value) in params {
+            parsed.header.push(Span::noise(" ".to_string()));
+            parsed.header.push(Span::field_key(name.to_string()));
+            parsed.header.push(Span::noise("=".to_string()));
+            parsed.header.push(Span::field_value(value.to_string()));
+            // only ", \ and ] are escaped; other backslashes are literal
+            let mut unescaped = String::new();
+            let mut chars = value[1..value.len() - 1].chars().peekable();
+            while let Some(c) = chars.next() {
+                match chars.peek() {
+                    Some(next @ ('"' | '\\' | ']')) if c == '\\' => {
+                        unescaped.push(*next);
+                        chars.next();
+                    }
+                    _ => unescaped.push(c),
+                }
             }
-            if last < rem.len() {
-                ret.push_span(Span::text(rem[last..].to_string()))?;
+            parsed.fields.push((format!("{id}.{name}"), unescaped));
+        }
+        parsed.header.push(Span::noise("]".to_string()));
+    }
+    match rem.strip_prefix(' ') {
+        Some(msg) => {
+            parsed.header.push(Span::noise(" ".to_string()));
+            parsed.text = msg.to_string();
+        }
+        None if rem.is_empty() => {}
+        None => return None,
+    }
+    Some(parsed)
+}
+
+/// Most lines a record can have; any more start a new record, so that a
+/// runaway one doesn't have to be held on to
+const MAX_RECORD_LINES: usize = 1000;
+
+/// Most lines of records that have been shown to keep track of for rewrapping
+const MAX_KEPT_LINES: usize = 100_000;
+
+/// Where a record starts, and the timestamp and level its lines go by
+#[derive(Debug, Clone, Copy)]
+struct Head {
+    lln: usize,
+    ts: Option<DateTime<Utc>>,
+    ll: Option<i32>,
+}
+
+/// A recognised line and the unrecognised lines after it, e.g. a panic
+/// message and its backtrace
+#[derive(Debug)]
+struct Record {
+    head: Head,
+    lines: Vec<ParsedLine>,
+    /// Whether the filter has let it through
+    shown: bool,
+    /// How many of lines have been wrapped and sent
+    sent: usize,
+}
+
+/// Groups lines into records, and filters and wraps them a record at a
+/// time, so that e.g. a backtrace goes along with the panic message before
+/// it, timestamp and all
+#[derive(Debug)]
+pub struct Records {
+    wrap: Wrap,
+    filter: LineFilter,
+    highlight: Option<Regex>,
+    /// The last record, which may yet get more lines
+    record: Option<Record>,
+    /// Records before it that were shown, oldest first, with how many lines
+    /// each, so that they can be wrapped again.  Their lines are the
+    /// feed's to keep, not ours.
+    kept: VecDeque<(Head, usize)>,
+    /// How many lines there are in kept, at most MAX_KEPT_LINES
+    kept_lines: usize,
+}
+
+impl Records {
+    pub fn new(wrap: Wrap, filter: LineFilter) -> Self {
+        Records {
+            wrap,
+            highlight: filter.highlight(),
+            filter,
+            record: None,
+            kept: VecDeque::new(),
+            kept_lines: 0,
+        }
+    }
+
+    /// Add logical line lln, wrapping into out whatever's ready to go
+    pub fn push(
+        &mut self,
+        lln: usize,
+        parsed: ParsedLine,
+        starts_record: bool,
+        out: &mut Vec<DisplayLine>,
+    ) {
+        if let Some(record) = &mut self.record {
+            if !starts_record && record.lines.len() < MAX_RECORD_LINES {
+                record.lines.push(parsed);
+                return;
+            }
+        }
+        self.flush(out);
+        // a record without a timestamp or level goes with the one before
+        let (ts, ll) = match &self.record {
+            Some(record) => (parsed.ts.or(record.head.ts), parsed.ll.or(record.head.ll)),
+            None => (parsed.ts, parsed.ll),
+        };
+        let head = Head { lln, ts, ll };
+        let record = Some(Record { head, lines: vec![parsed], shown: false, sent: 0 });
+        if let Some(done) = std::mem::replace(&mut self.record, record) {
+            if done.shown {
+                self.keep(done);
+            }
+        }
+    }
+
+    fn keep(&mut self, record: Record) {
+        self.kept_lines += record.sent;
+        self.kept.push_back((record.head, record.sent));
+        while self.kept_lines > MAX_KEPT_LINES {
+            let Some((_, oldest)) = self.kept.pop_front() else {
+                break;
+            };
+            self.kept_lines -= oldest;
+        }
+    }
+
+    /// Wrap everything that's been sent again at a different width, or as
+    /// much of it as is still in parsed, the lines by lln in order
+    pub fn rewrap(
+        &mut self,
+        cols: usize,
+        parsed: &[(usize, ParsedLine)],
+    ) -> Vec<DisplayLine> {
+        self.wrap.cols = cols;
+        let mut out = vec![];
+        for (head, sent) in &self.kept {
+            // only records that are there in full
+            let i = parsed.partition_point(|(lln, _)| *lln < head.lln);
+            let Some(lines) = parsed.get(i..i + sent) else {
+                continue;
+            };
+            if lines[0].0 == head.lln && lines[sent - 1].0 == head.lln + sent - 1 {
+                self.wrap(head, 0, lines.iter().map(|(_, parsed)| parsed), &mut out);
+            }
+        }
+        if let Some(record) = &self.record {
+            self.wrap(&record.head, 0, &record.lines[..record.sent], &mut out);
+        }
+        out
+    }
+
+    /// Wrap lines of a record into out, starting from its first'th
+    fn wrap<'a>(
+        &self,
+        head: &Head,
+        first: usize,
+        lines: impl IntoIterator<Item = &'a ParsedLine>,
+        out: &mut Vec<DisplayLine>,
+    ) {
+        let regex = self.highlight.as_ref();
+        for (i, parsed) in lines.into_iter().enumerate() {
+            let lines = display_lines(head.lln + first + i, self.wrap, parsed, regex);
+            out.extend(lines.into_iter().map(|line| DisplayLine {
+                record: head.lln,
+                ll: head.ll,
+                ts: head.ts,
+                ..line
+            }));
+        }
+    }
+
+    /// Wrap into out whatever's been let through of the last record and not
+    /// yet sent.  If more lines for it come along later, they're sent then;
+    /// if it wasn't let through, they get another chance to match.
+    pub fn flush(&mut self, out: &mut Vec<DisplayLine>) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+        let Head { ts, ll, .. } = record.head;
+        record.shown = record.shown
+            || (self.filter.in_window(ts)
+                && self.filter.at_level(ll)
+                && record.lines.iter().any(|parsed| self.filter.matches(parsed))
+                && self.filter.query_matches(ts, ll, &record.lines));
+        if record.shown {
+            self.wrap(&record.head, record.sent, &record.lines[record.sent..], out);
+            record.sent = record.lines.len();
+        }
+        self.record = Some(record);
+    }
+}
+
+/// Wrap a parsed line into display lines, highlighting filter matches
+pub fn display_lines(
+    lln: usize,
+    wrap: Wrap,
+    parsed: &ParsedLine,
+    filter: Option<&Regex>,
+) -> Vec<DisplayLine> {
+    let mut spans = parsed.header.clone();
+    push_highlighted(&mut spans, Span::text(parsed.text.clone()), filter);
+    for span in &parsed.trailer {
+        if span.label == SpanLabel::FieldValue {
+            push_highlighted(&mut spans, span.clone(), filter);
+        } else {
+            spans.push(span.clone());
+        }
+    }
+    let mut ret = DisplayLinesBuilder::new(lln, wrap.cols);
+    ret.ts = parsed.ts;
+    ret.ll = parsed.ll;
+    if wrap.hanging_indent {
+        let header =
+            parsed.header.iter().map(|span| display_width(&span.text)).sum::<usize>();
+        let message = parsed.text.trim_start();
+        ret.indent =
+            header + display_width(&parsed.text[..parsed.text.len() - message.len()]);
+    }
+    for span in styled(spans, &parsed.styles) {
+        ret.push_span(span);
+    }
+    ret.build()
+}
+
+/// Push span, split up so that filter matches are labelled as such
+fn push_highlighted(spans: &mut Vec<Span>, span: Span, filter: Option<&Regex>) {
+    let Some(filter) = filter else {
+        spans.push(span);
+        return;
+    };
+    let text = span.text.as_str();
+    let mut last = 0;
+    for m in filter.find_iter(text) {
+        spans.push(Span { text: text[last..m.start()].to_string(), ..span });
+        spans.push(Span::text_match(m.as_str().to_string()));
+        last = m.end();
+    }
+    if last < text.len() {
+        spans.push(Span { text: text[last..].to_string(), ..span });
+    }
+}
+
+/// Split spans up wherever the style changes, and style the pieces
+fn styled(spans: Vec<Span>, styles: &[(usize, Style)]) -> Vec<Span> {
+    if styles.is_empty() {
+        return spans;
+    }
+    let mut ret = vec![];
+    let mut styles = styles.iter().peekable();
+    let mut style = Style::default();
+    let mut offset = 0;
+    for span in spans {
+        let end = offset + span.text.len();
+        let mut start = offset;
+        while let Some((at, next)) = styles.next_if(|(at, _)| *at < end) {
+            if *at > start {
+                let text = span.text[start - offset..at - offset].to_string();
+                ret.push(Span { text, style, ..span });
+                start = *at;
             }
+            style = *next;
         }
-        None => {
-            ret.push_span(Span::text(rem.to_string()))?;
+        if start < end {
+            let text = span.text[start - offset..].to_string();
+            ret.push(Span { text, style, ..span });
         }
+        offset = end;
     }
-    Ok(Some(ret.build()))
+    ret
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::prelude::*;
+
+    fn parse_log_line(
+        parser: &LineParser,
+        lln: usize,
+        cols: usize,
+        line: &str,
+        filter: Option<&Regex>,
+    ) -> Result<Option<Vec<DisplayLine>>> {
+        let parsed = parser.parse(line)?;
+        if let Some(filter) = filter {
+            let filter = LineFilter { regex: Some(filter.clone()), ..Default::default() };
+            if !filter.matches(&parsed) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(display_lines(lln, Wrap::cols(cols), &parsed, filter)))
+    }
 
     fn melt(lines: Vec<DisplayLine>) -> String {
         lines
@@ -256,12 +1443,13 @@ mod test {
     #[test]
     fn test_parse_log_line() -> Result<()> {
         let s = "[2024-02-25T20:49:42Z TRACE s8] Petersburg, used only by the elite";
-        let r = parse_log_line(0, 80, s, None)?.unwrap();
+        let r = parse_log_line(&LineParser::EnvLogger, 0, 80, s, None)?.unwrap();
         let ts: DateTime<Utc> = "2024-02-25T20:49:42Z".parse()?;
         assert_eq!(
             r,
             vec![DisplayLine {
                 lln: 0,
+                record: 0,
                 ll: Some(4),
                 ts: Some(ts),
                 spans: vec![
@@ -277,23 +1465,359 @@ mod test {
             }]
         );
         // test soft breaks
-        let r = parse_log_line(0, 100, s, None)?.unwrap();
+        let r = parse_log_line(&LineParser::EnvLogger, 0, 100, s, None)?.unwrap();
         assert_eq!(melt(r), s);
-        let r = parse_log_line(0, 40, s, None)?.unwrap();
+        let r = parse_log_line(&LineParser::EnvLogger, 0, 40, s, None)?.unwrap();
         assert_eq!(
             melt(r),
             ["[2024-02-25T20:49:42Z TRACE s8] ", "Petersburg, used only by the elite"]
                 .join("\n")
         );
-        let r = parse_log_line(0, 1, s, None)?.unwrap();
+        // spaces that don't fit at the end of a row are dropped
+        let r = parse_log_line(&LineParser::EnvLogger, 0, 1, s, None)?.unwrap();
         assert_eq!(
             melt(r),
-            s.chars().map(|c| c.to_string()).collect::<Vec<String>>().join("\n")
+            s.chars()
+                .filter(|c| *c != ' ')
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
         );
         // make sure it doesn't stack overflow
         for i in 1..=100 {
-            parse_log_line(0, i, s, None)?;
+            parse_log_line(&LineParser::EnvLogger, 0, i, s, None)?;
         }
         Ok(())
     }
+
+    #[test]
+    fn test_wrap() -> Result<()> {
+        let s = "[2024-02-25T20:49:42Z INFO s8] sent fill-or-kill to gw, took 3ms";
+        let parsed = LineParser::EnvLogger.parse(s)?;
+        // as many words as fit, with no breaks at the edges of matches
+        let r = display_lines(0, Wrap::cols(50), &parsed, Some(&Regex::new("ll|k")?));
+        assert_eq!(
+            melt(r),
+            "[2024-02-25T20:49:42Z INFO s8] sent fill-or-kill \nto gw, took 3ms"
+        );
+        // hyphens are somewhere to break too
+        let r = display_lines(0, Wrap::cols(42), &parsed, None);
+        assert_eq!(
+            melt(r),
+            "[2024-02-25T20:49:42Z INFO s8] sent fill-\nor-kill to gw, took 3ms"
+        );
+        // continuation rows line up under the message
+        let wrap = Wrap { cols: 64, hanging_indent: true };
+        let parsed =
+            LineParser::EnvLogger.parse(&format!("{s}, the gateway says it was busy"))?;
+        let r = display_lines(0, wrap, &parsed, None);
+        assert_eq!(
+            melt(r.clone()),
+            [
+                "[2024-02-25T20:49:42Z INFO s8] sent fill-or-kill to gw, took ",
+                "                               3ms, the gateway says it was busy"
+            ]
+            .join("\n")
+        );
+        assert!(
+            r[1].spans[0].label == SpanLabel::Noise
+                && r[1].spans[1].label == SpanLabel::Text
+        );
+        // unless that leaves hardly any room
+        let r = display_lines(0, Wrap { cols: 60, ..wrap }, &parsed, None);
+        assert_eq!(
+            melt(r),
+            [
+                "[2024-02-25T20:49:42Z INFO s8] sent fill-or-kill to gw, took",
+                "3ms, the gateway says it was busy"
+            ]
+            .join("\n")
+        );
+        // tabs go to the next tab stop
+        let parsed = ParsedLine::plain("a\tbc\td\tef");
+        let r = display_lines(0, Wrap::cols(20), &parsed, None);
+        assert_eq!(melt(r), "a       bc      d   \nef");
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide() {
+        let rows = |text: &str, cols| {
+            let r = display_lines(0, Wrap::cols(cols), &ParsedLine::plain(text), None);
+            r.into_iter().map(|line| melt(vec![line])).collect::<Vec<_>>()
+        };
+        // double width glyphs don't straddle the edge, they go on the next row
+        assert_eq!(rows("日本語のテキスト", 5), vec!["日本", "語の", "テキ", "スト"]);
+        assert_eq!(rows("ab🚀🚀", 3), vec!["ab", "🚀", "🚀"]);
+        // an emoji sequence is one glyph, and combining marks and zero width
+        // spaces take no room
+        assert_eq!(
+            rows("👩\u{200d}💻🇯🇵e\u{301}\u{200b}x", 4),
+            vec!["👩\u{200d}💻🇯🇵", "e\u{301}\u{200b}x"]
+        );
+        // rows only narrower than a glyph get one each anyway
+        assert_eq!(rows("日本", 1), vec!["日", "本"]);
+    }
+
+    /// Mixed-width text: ASCII, CJK, emoji and emoji sequences, combining
+    /// marks, zero width spaces, and places to break
+    fn mixed_width() -> impl Strategy<Value = String> {
+        let g = prop_oneof![
+            "[a-z0-9]{1,4}",
+            "[\u{4e00}-\u{4fff}]",
+            "[\u{1f600}-\u{1f64f}]",
+            Just("👩\u{200d}💻".to_string()),
+            Just("🇯🇵".to_string()),
+            Just("e\u{301}".to_string()),
+            Just("\u{200b}".to_string()),
+            Just(" ".to_string()),
+            Just("\t".to_string()),
+            Just("-".to_string()),
+        ];
+        prop::collection::vec(g, 0..40).prop_map(|gs| gs.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_wrap(text in mixed_width(), cols in 1usize..80, hanging_indent: bool) {
+            let mut parsed = LineParser::EnvLogger.parse(&format!("[2024-02-25T20:49:42Z INFO s8] {text}")).unwrap();
+            if cols % 2 == 0 {
+                parsed = ParsedLine::plain(&text);
+            }
+            let r = display_lines(0, Wrap { cols, hanging_indent }, &parsed, None);
+            let rows = r.iter().map(|line| melt(vec![line.clone()])).collect::<Vec<_>>();
+            for row in &rows {
+                // nothing wider than cols, unless it's one glyph that is
+                let glyphs = row.graphemes(true).filter(|g| grapheme_width(g) > 0).count();
+                prop_assert!(display_width(row) <= cols || glyphs == 1, "{row:?} at {cols}");
+            }
+            // nothing but whitespace goes missing
+            let visible = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+            let line = parsed.header.iter().map(|span| span.text.as_str()).collect::<String>() + &parsed.text;
+            prop_assert_eq!(visible(&rows.concat()), visible(&line));
+            // and no grapheme is split between rows
+            let graphemes = rows.iter().map(|row| row.graphemes(true).count()).sum::<usize>();
+            prop_assert_eq!(graphemes, rows.concat().graphemes(true).count());
+        }
+    }
+
+    #[test]
+    fn test_ansi() -> Result<()> {
+        let red = Style { fg: Some(ansi::Color::Indexed(1)), ..Default::default() };
+        let bold = Style { bold: true, ..Default::default() };
+        let s = "\x1b[2m[\x1b[0m2024-02-25T20:49:42Z \x1b[31mERROR\x1b[0m s8\x1b[2m]\x1b[0m \x1b[1mfailed\x1b[0m to fill";
+        let parsed = LineParser::EnvLogger.parse(s)?;
+        assert_eq!((parsed.ll, parsed.text.as_str()), (Some(0), " failed to fill"));
+        assert!(LineParser::EnvLogger.starts_record(s, &parsed));
+        // widths don't count the escapes, so this fits
+        let r = display_lines(0, Wrap::cols(46), &parsed, Some(&Regex::new("ail")?));
+        assert_eq!(melt(r.clone()), "[2024-02-25T20:49:42Z ERROR s8] failed to fill");
+        let spans = r[0].spans.iter().map(|s| (s.text.as_str(), s.label, s.style));
+        assert_eq!(
+            spans.skip(3).collect::<Vec<_>>(),
+            vec![
+                ("ERROR", SpanLabel::Level, red),
+                (" ", SpanLabel::Noise, Style::default()),
+                ("s8", SpanLabel::Target, Style::default()),
+                ("]", SpanLabel::Noise, Style::default()),
+                (" ", SpanLabel::Text, Style::default()),
+                ("f", SpanLabel::Text, bold),
+                ("ail", SpanLabel::TextMatch, bold),
+                ("ed", SpanLabel::Text, bold),
+                (" to fill", SpanLabel::Text, Style::default()),
+            ]
+        );
+        // escapes that only turn up once JSON's been unescaped
+        let parser = LineParser::new(&serde_yaml::from_str("type: json")?)?;
+        let parsed =
+            parser.parse(r#"{"level":"info","msg":"\u001b[32mok\u001b[0m","n":1}"#)?;
+        assert_eq!(parsed.text, "ok");
+        let r = display_lines(0, Wrap::cols(80), &parsed, None);
+        assert_eq!(melt(r.clone()), "info ok n=1");
+        let green = Style { fg: Some(ansi::Color::Indexed(2)), ..Default::default() };
+        assert_eq!(r[0].spans[2], Span { style: green, ..Span::text("ok".to_string()) });
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_format() -> Result<()> {
+        let format: Format = serde_yaml::from_str(
+            r#"
+            type: regex
+            regex: '^(?P<ts>\S+ \S+) <(?P<thread>\d+)> (?P<level>\w+) (?P<target>[\w:]+): (?P<msg>.*)'
+            ts_format: '%Y-%m-%d %H:%M:%S%.f'
+            levels: { E: error, W: warn, I: info }
+            "#,
+        )?;
+        let parser = LineParser::new(&format)?;
+        let parsed =
+            parser.parse("2024-03-01 14:02:00.250 <17> W gw::conn: slow: 80ms")?;
+        assert_eq!(parsed.ts, Some("2024-03-01T14:02:00.250Z".parse()?));
+        assert_eq!(parsed.ll, Some(1));
+        assert_eq!(
+            parsed.header,
+            vec![
+                Span::timestamp("2024-03-01 14:02:00.250".to_string()),
+                Span::noise(" <".to_string()),
+                Span::field_value("17".to_string()),
+                Span::noise("> ".to_string()),
+                Span::level("W".to_string()),
+                Span::noise(" ".to_string()),
+                Span::target("gw::conn".to_string()),
+                Span::noise(": ".to_string()),
+            ]
+        );
+        assert_eq!(parsed.text, "slow: 80ms");
+        // the usual level names work too
+        assert_eq!(parser.parse("2024-03-01 14:02:01 <1> DEBUG x: y")?.ll, Some(3));
+        assert_eq!(parser.parse("2024-03-01 14:02:01 <1> X x: y")?.ll, None);
+        assert_eq!(parser.parse("  at foo.rs:12")?, ParsedLine::plain("  at foo.rs:12"));
+        assert!(LineParser::new(&serde_yaml::from_str("{ type: regex, regex: '(' }")?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_format() -> Result<()> {
+        let parser = LineParser::new(&serde_yaml::from_str("type: json")?)?;
+        // tracing-subscriber
+        let line = r#"{"timestamp":"2024-03-01T14:02:00.25Z","level":"WARN","fields":{"message":"slow","ms":80},"target":"gw"}"#;
+        let parsed = parser.parse(line)?;
+        assert_eq!(parsed.ts, Some("2024-03-01T14:02:00.250Z".parse()?));
+        assert_eq!(parsed.ll, Some(1));
+        assert_eq!(parsed.text, "slow");
+        assert_eq!(parsed.fields, vec![("fields.ms".to_string(), "80".to_string())]);
+        let r = display_lines(0, Wrap::cols(80), &parsed, Some(&Regex::new("8")?));
+        assert_eq!(melt(r.clone()), "2024-03-01T14:02:00.25Z WARN gw slow fields.ms=80");
+        let labels = r[0].spans.iter().rev().take(4).map(|s| s.label).collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                SpanLabel::FieldValue,
+                SpanLabel::TextMatch,
+                SpanLabel::Noise,
+                SpanLabel::FieldKey
+            ]
+        );
+        // bunyan, with numeric levels
+        let line = r#"{"name":"risk","hostname":"h","level":50,"msg":"rejected","time":"2024-03-01T14:02:01Z","v":0}"#;
+        let parsed = parser.parse(line)?;
+        assert_eq!((parsed.ll, parsed.text.as_str()), (Some(0), "rejected"));
+        assert_eq!(parsed.header[4], Span::target("risk".to_string()));
+        // configured keys, and epoch millis
+        let parser = LineParser::new(&serde_yaml::from_str(
+            "{ type: json, ts_key: at, level_key: sev, msg_key: body.text }",
+        )?)?;
+        let parsed =
+            parser.parse(r#"{"at":1709301720250,"sev":"error","body":{"text":"x"}}"#)?;
+        assert_eq!(parsed.ts, Some("2024-03-01T14:02:00.250Z".parse()?));
+        assert_eq!((parsed.ll, parsed.text.as_str()), (Some(0), "x"));
+        assert_eq!(parser.parse("not json")?, ParsedLine::plain("not json"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_logfmt_format() -> Result<()> {
+        let parser = LineParser::new(&serde_yaml::from_str("type: logfmt")?)?;
+        let line = r#"ts=2024-03-01T14:02:00Z lvl=eror msg="order \"7\" rejected" id=7 why="no margin" dry"#;
+        let parsed = parser.parse(line)?;
+        assert_eq!(parsed.ts, Some("2024-03-01T14:02:00Z".parse()?));
+        assert_eq!(parsed.ll, Some(0));
+        assert_eq!(parsed.text, r#"order "7" rejected"#);
+        let fields = vec![
+            ("id".to_string(), "7".to_string()),
+            ("why".to_string(), "no margin".to_string()),
+        ];
+        assert_eq!(parsed.fields, fields);
+        assert_eq!(parsed.trailer[8], Span::field_value(r#""no margin""#.to_string()));
+        let r = display_lines(0, Wrap::cols(200), &parsed, None);
+        assert_eq!(melt(r), line.replace(r#"\"7\""#, r#""7""#));
+        // the message filters like the same value as a field would
+        let filter =
+            LineFilter { regex: Some(Regex::new(r#"order "7""#)?), ..Default::default() };
+        assert!(filter.matches(&parsed));
+        let parsed = parser.parse(r#"msg="say \"hi\"" echo="say \"hi\"""#)?;
+        assert_eq!(
+            (parsed.text.as_str(), parsed.fields[0].1.as_str()),
+            (r#"say "hi""#, r#"say "hi""#)
+        );
+        // lines that aren't all pairs
+        for line in ["just words", r#"a="unterminated"#, "[x] a=b", ""] {
+            assert_eq!(parser.parse(line)?, ParsedLine::plain(line), "{line}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_syslog_format() -> Result<()> {
+        let parser = SyslogParser { reference: "2024-03-01T14:02:00Z".parse()? };
+        let line = r#"<165>1 2003-10-11T22:14:15.003Z host evntslog - ID47 [ex@32473 iut="3" src="A\"pp"][b@1] An event"#;
+        let parsed = parser.parse(line);
+        assert_eq!(parsed.ts, Some("2003-10-11T22:14:15.003Z".parse()?));
+        assert_eq!((parsed.ll, parsed.text.as_str()), (Some(2), "An event"));
+        assert_eq!(parsed.header[7], Span::target("evntslog".to_string()));
+        let fields = parsed.fields.iter().map(|(k, v)| format!("{k}={v}"));
+        assert_eq!(
+            fields.collect::<Vec<_>>(),
+            vec!["host=host", "msgid=ID47", "ex@32473.iut=3", r#"ex@32473.src=A"pp"#]
+        );
+        let r = display_lines(0, Wrap::cols(200), &parsed, None);
+        assert_eq!(melt(r), line);
+        let parsed = parser.parse("<11>1 - - - - - -");
+        assert_eq!((parsed.ts, parsed.ll, parsed.text.as_str()), (None, Some(0), ""));
+        // RFC 3164, with and without PRI, and the year from the reference
+        let line = "<34>Oct 11 22:14:15 mymachine su: 'su root' failed";
+        let parsed = parser.parse(line);
+        assert_eq!(parsed.ts, Some("2023-10-11T22:14:15Z".parse()?));
+        assert_eq!((parsed.ll, parsed.text.as_str()), (Some(0), "'su root' failed"));
+        assert_eq!(melt(display_lines(0, Wrap::cols(200), &parsed, None)), line);
+        let parsed = parser.parse("Mar  1 14:02:00 host sshd[123]: Accepted");
+        assert_eq!(parsed.ts, Some("2024-03-01T14:02:00Z".parse()?));
+        assert_eq!(parsed.header[4], Span::target("sshd[123]".to_string()));
+        let parser = SyslogParser { reference: "2024-12-31T23:59:00Z".parse()? };
+        let parsed = parser.parse("Jan  1 00:00:01 host cron: tick");
+        assert_eq!(parsed.ts, Some("2025-01-01T00:00:01Z".parse()?));
+        // up to a month ahead of the reference, and it can move on
+        let mut parser = LineParser::new(&Format::Syslog)?;
+        parser.set_reference("2024-03-01T00:00:00Z".parse()?);
+        let line = "Mar 20 00:00:00 host cron: tick";
+        assert_eq!(parser.parse(line)?.ts, Some("2024-03-20T00:00:00Z".parse()?));
+        let line = "Apr 20 00:00:00 host cron: tick";
+        assert_eq!(parser.parse(line)?.ts, Some("2023-04-20T00:00:00Z".parse()?));
+        parser.set_reference("2024-04-20T00:00:00Z".parse()?);
+        assert_eq!(parser.parse(line)?.ts, Some("2024-04-20T00:00:00Z".parse()?));
+        let parser = SyslogParser { reference: "2024-12-31T23:59:00Z".parse()? };
+        // rsyslog's high-precision timestamps
+        let parsed = parser.parse("2024-03-01T14:02:00.123456+01:00 host app: x");
+        assert_eq!(parsed.ts, Some("2024-03-01T13:02:00.123456Z".parse()?));
+        assert_eq!(parsed.ll, None);
+        for line in ["hello world", "Mar  1 14:02:00 no tag", "<999>1 - - - - - -"] {
+            assert_eq!(parser.parse(line), ParsedLine::plain(line), "{line}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect() {
+        let env_logger = [
+            "[2024-03-01T14:02:00Z INFO gw] starting",
+            "thread 'main' panicked at src/main.rs:3:5:",
+            "stack backtrace:",
+            "   0: rust_begin_unwind",
+            "   1: core::panicking::panic_fmt",
+        ];
+        assert_eq!(detect(&env_logger), Format::EnvLogger);
+        let json = [r#"{"level":"info","msg":"a"}"#, "", r#"{"level":"warn","msg":"b"}"#];
+        assert_eq!(detect(&json), Format::Json(JsonFormat::default()));
+        let logfmt =
+            ["ts=2024-03-01T14:02:00Z level=info msg=a", "level=warn msg=\"b c\""];
+        assert_eq!(detect(&logfmt), Format::Logfmt);
+        let syslog = ["Mar  1 14:02:00 host sshd[1]: a", "Mar  1 14:02:01 host cron: b"];
+        assert_eq!(detect(&syslog), Format::Syslog);
+        // bare words are valid logfmt keys, but one line in eleven isn't enough
+        let mut text = vec!["just some text"; 10];
+        text.push("a=b and text");
+        assert_eq!(detect(&text), Format::Plain);
+        assert_eq!(detect(&[]), Format::Plain);
+    }
 }
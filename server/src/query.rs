@@ -0,0 +1,426 @@
+//! Filter queries, e.g.
+//!
+//! ```text
+//! level>=warn AND target:order_gateway AND NOT "heartbeat" AND /timeout \d+ms/
+//! ```
+//!
+//! Terms are combined with AND, OR and NOT, AND binding tighter than OR, and
+//! grouped with parentheses.  Terms next to each other are ANDed.
+//!
+//! - `word` or `"quoted words"`: the message or a field value contains it
+//! - `/regex/`, or `/regex/i` to ignore case: the message or a field value
+//!   matches it
+//! - `level>=warn`: the record is at least that severe; any of `: = != > >=
+//!   < <=` work, with `:` the same as `=`
+//! - `ts>=2024-03-01T14:02:00Z`: likewise for the record's timestamp
+//! - `target:gw`, `key:value`: the target or field contains value; `=` for
+//!   exactly value, `!=` for anything else, and `> >= < <=` to compare, as
+//!   numbers if they both are
+//!
+//! Words and regexes, unless NOTted, are what's highlighted as matches.
+
+use crate::parser::{parse_level, ParsedLine, SpanLabel};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped, is_not, tag, take_while1},
+    character::complete::{anychar, char, multispace0, multispace1},
+    combinator::{all_consuming, cut, map, opt, peek, recognize, verify},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+use regex::Regex;
+use std::{cmp::Ordering, fmt};
+
+#[derive(Debug, Clone)]
+pub struct Query {
+    expr: Expr,
+    /// The words and regexes looked for, to highlight
+    highlight: Option<Regex>,
+}
+
+/// What's wrong with a query, and where
+#[derive(Debug)]
+pub struct QueryError {
+    /// What's bad, e.g. "regex"
+    what: &'static str,
+    /// Counting characters from 1
+    pub column: usize,
+    detail: Option<String>,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad {} at column {}", self.what, self.column)?;
+        match &self.detail {
+            Some(detail) => write!(f, ": {detail}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    /// A word or regex to look for in the message and field values
+    Text(Regex),
+    Level(Op, i32),
+    Ts(Op, DateTime<Utc>),
+    Field(String, Op, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Contains,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Op {
+    fn test(self, ord: Ordering) -> bool {
+        match self {
+            Op::Contains | Op::Eq => ord.is_eq(),
+            Op::Ne => ord.is_ne(),
+            Op::Gt => ord.is_gt(),
+            Op::Ge => ord.is_ge(),
+            Op::Lt => ord.is_lt(),
+            Op::Le => ord.is_le(),
+        }
+    }
+}
+
+/// A query as parsed, before its values are checked and regexes compiled;
+/// slices of the query, so that errors can say where they are
+#[derive(Debug)]
+enum Raw<'a> {
+    And(Vec<Raw<'a>>),
+    Or(Vec<Raw<'a>>),
+    Not(Box<Raw<'a>>),
+    Word(&'a str),
+    Quoted(&'a str),
+    Regex(&'a str, bool),
+    Cmp(&'a str, Op, &'a str),
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || "_.-@".contains(c)
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && c != '(' && c != ')' && c != '"'
+}
+
+fn quoted(i: &str) -> IResult<&str, &str> {
+    recognize(delimited(
+        char('"'),
+        opt(escaped(is_not("\\\""), '\\', anychar)),
+        char('"'),
+    ))(i)
+}
+
+fn word(i: &str) -> IResult<&str, &str> {
+    verify(take_while1(is_word_char), |w: &str| !["AND", "OR", "NOT"].contains(&w))(i)
+}
+
+fn op(i: &str) -> IResult<&str, Op> {
+    alt((
+        map(tag(">="), |_| Op::Ge),
+        map(tag("<="), |_| Op::Le),
+        map(tag("!="), |_| Op::Ne),
+        map(tag(">"), |_| Op::Gt),
+        map(tag("<"), |_| Op::Lt),
+        map(tag("="), |_| Op::Eq),
+        map(tag(":"), |_| Op::Contains),
+    ))(i)
+}
+
+fn atom(i: &str) -> IResult<&str, Raw<'_>> {
+    alt((
+        // once there's a paren, there had better be a query and another one
+        preceded(
+            pair(char('('), multispace0),
+            cut(terminated(or, pair(multispace0, char(')')))),
+        ),
+        map(
+            pair(
+                delimited(
+                    char('/'),
+                    recognize(many0(alt((tag("\\/"), is_not("\\/"), tag("\\"))))),
+                    char('/'),
+                ),
+                opt(char('i')),
+            ),
+            |(pattern, i)| Raw::Regex(pattern, i.is_some()),
+        ),
+        map(quoted, Raw::Quoted),
+        map(
+            tuple((take_while1(is_key_char), op, alt((quoted, word)))),
+            |(key, op, value)| Raw::Cmp(key, op, value),
+        ),
+        map(word, Raw::Word),
+    ))(i)
+}
+
+fn not(i: &str) -> IResult<&str, Raw<'_>> {
+    alt((
+        map(
+            preceded(terminated(tag("NOT"), alt((multispace1, peek(tag("("))))), not),
+            |raw| Raw::Not(Box::new(raw)),
+        ),
+        atom,
+    ))(i)
+}
+
+fn and(i: &str) -> IResult<&str, Raw<'_>> {
+    let sep = alt((delimited(multispace1, tag("AND"), multispace1), multispace1));
+    let (i, first) = not(i)?;
+    let (i, mut rest) = many0(preceded(sep, not))(i)?;
+    if rest.is_empty() {
+        return Ok((i, first));
+    }
+    rest.insert(0, first);
+    Ok((i, Raw::And(rest)))
+}
+
+fn or(i: &str) -> IResult<&str, Raw<'_>> {
+    let (i, first) = and(i)?;
+    let (i, mut rest) =
+        many0(preceded(delimited(multispace1, tag("OR"), multispace1), and))(i)?;
+    if rest.is_empty() {
+        return Ok((i, first));
+    }
+    rest.insert(0, first);
+    Ok((i, Raw::Or(rest)))
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Self> {
+        let raw = match all_consuming(delimited(multispace0, or, multispace0))(s) {
+            Ok((_, raw)) => raw,
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => bail!(QueryError {
+                what: "query",
+                column: s[..s.len() - e.input.len()].chars().count() + 1,
+                detail: None,
+            }),
+            Err(nom::Err::Incomplete(_)) => bail!("incomplete query"),
+        };
+        let mut highlights = vec![];
+        let expr = compile(s, raw, &mut highlights)?;
+        let highlight = match highlights.is_empty() {
+            true => None,
+            false => Some(Regex::new(&highlights.join("|"))?),
+        };
+        Ok(Query { expr, highlight })
+    }
+
+    /// The words and regexes the query looks for, for highlighting
+    pub fn highlight(&self) -> Option<&Regex> {
+        self.highlight.as_ref()
+    }
+
+    /// Whether a record, with timestamp ts, level ll and lines lines,
+    /// matches
+    pub fn matches(
+        &self,
+        ts: Option<DateTime<Utc>>,
+        ll: Option<i32>,
+        lines: &[ParsedLine],
+    ) -> bool {
+        self.expr.matches(ts, ll, lines)
+    }
+}
+
+/// Check and compile a raw query, collecting what to highlight (as regexes
+/// that can be ORed together) in highlights
+fn compile(s: &str, raw: Raw<'_>, highlights: &mut Vec<String>) -> Result<Expr> {
+    let column = |slice: &str| {
+        s[..slice.as_ptr() as usize - s.as_ptr() as usize].chars().count() + 1
+    };
+    let text = |pattern: String, highlights: &mut Vec<String>| {
+        let regex = Regex::new(&pattern);
+        highlights.push(format!("(?:{pattern})"));
+        regex.map(Expr::Text)
+    };
+    match raw {
+        Raw::And(raws) => Ok(Expr::And(
+            raws.into_iter()
+                .map(|raw| compile(s, raw, highlights))
+                .collect::<Result<_>>()?,
+        )),
+        Raw::Or(raws) => Ok(Expr::Or(
+            raws.into_iter()
+                .map(|raw| compile(s, raw, highlights))
+                .collect::<Result<_>>()?,
+        )),
+        // what's NOTted isn't there to highlight
+        Raw::Not(raw) => Ok(Expr::Not(Box::new(compile(s, *raw, &mut vec![])?))),
+        Raw::Word(word) => Ok(text(regex::escape(word), highlights)?),
+        Raw::Quoted(quoted) => Ok(text(regex::escape(&unquote(quoted)), highlights)?),
+        Raw::Regex(slice, icase) => {
+            let pattern = slice.replace("\\/", "/");
+            let pattern = if icase { format!("(?i){pattern}") } else { pattern };
+            match text(pattern, highlights) {
+                Ok(expr) => Ok(expr),
+                Err(e) => bail!(QueryError {
+                    what: "regex",
+                    column: column(slice),
+                    detail: Some(e.to_string()),
+                }),
+            }
+        }
+        Raw::Cmp(key, op, value) => {
+            let unquoted = unquote(value);
+            match key {
+                "level" => match parse_level(&unquoted) {
+                    Some(ll) => Ok(Expr::Level(op, ll)),
+                    None => bail!(QueryError {
+                        what: "level",
+                        column: column(value),
+                        detail: Some(unquoted),
+                    }),
+                },
+                "ts" => match unquoted.parse() {
+                    Ok(ts) => Ok(Expr::Ts(op, ts)),
+                    Err(e) => bail!(QueryError {
+                        what: "timestamp",
+                        column: column(value),
+                        detail: Some(e.to_string()),
+                    }),
+                },
+                _ => Ok(Expr::Field(key.to_string(), op, unquoted)),
+            }
+        }
+    }
+}
+
+/// The contents of a quoted value, or an unquoted one as is
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|inner| inner.strip_suffix('"')) {
+        Some(inner) => serde_json::from_str(s).unwrap_or_else(|_| inner.to_string()),
+        None => s.to_string(),
+    }
+}
+
+/// The values a record has for key: the target, the message, or a field
+fn field_values(key: &str, lines: &[ParsedLine]) -> Vec<String> {
+    match key {
+        // formats like logfmt have it as a field instead
+        "target" => lines
+            .iter()
+            .take(1)
+            .flat_map(|parsed| &parsed.header)
+            .filter(|span| span.label == SpanLabel::Target)
+            .map(|span| span.text.clone())
+            .chain(lines.iter().take(1).flat_map(|parsed| {
+                parsed.fields.iter().filter(|(k, _)| k == key).map(|(_, v)| v.clone())
+            }))
+            .collect(),
+        "msg" | "message" => {
+            lines.iter().map(|parsed| parsed.text.trim().to_string()).collect()
+        }
+        _ => lines
+            .iter()
+            .flat_map(|parsed| &parsed.fields)
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+            .collect(),
+    }
+}
+
+/// Compare as numbers if they both are, or else as strings
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+impl Expr {
+    fn matches(
+        &self,
+        ts: Option<DateTime<Utc>>,
+        ll: Option<i32>,
+        lines: &[ParsedLine],
+    ) -> bool {
+        match self {
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(ts, ll, lines)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(ts, ll, lines)),
+            Expr::Not(expr) => !expr.matches(ts, ll, lines),
+            Expr::Text(regex) => lines.iter().any(|parsed| {
+                regex.is_match(&parsed.text)
+                    || parsed.fields.iter().any(|(_, value)| regex.is_match(value))
+            }),
+            // severity goes the other way to ll
+            Expr::Level(op, want) => ll.is_some_and(|ll| op.test(want.cmp(&ll))),
+            Expr::Ts(op, want) => ts.is_some_and(|ts| op.test(ts.cmp(want))),
+            Expr::Field(key, op, want) => {
+                field_values(key, lines).iter().any(|value| match op {
+                    Op::Contains => value.contains(want.as_str()),
+                    _ => op.test(compare(value, want)),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::LineParser;
+
+    fn record(lines: &[&str]) -> (Option<DateTime<Utc>>, Option<i32>, Vec<ParsedLine>) {
+        let parser =
+            LineParser::new(&serde_yaml::from_str("type: logfmt").unwrap()).unwrap();
+        let lines =
+            lines.iter().map(|line| parser.parse(line).unwrap()).collect::<Vec<_>>();
+        (lines[0].ts, lines[0].ll, lines)
+    }
+
+    fn matches(query: &str, lines: &[&str]) -> Result<bool> {
+        let (ts, ll, lines) = record(lines);
+        Ok(Query::parse(query)?.matches(ts, ll, &lines))
+    }
+
+    #[test]
+    fn test_query() -> Result<()> {
+        let q = r#"level>=warn AND target:order_gateway AND NOT "heartbeat" AND /timeout \d+ms/"#;
+        let line = "ts=2024-03-01T14:02:00Z level=warn target=order_gateway msg=\"timeout 80ms\"";
+        assert!(matches(q, &[line])?);
+        assert!(!matches(q, &[&line.replace("warn", "info")])?);
+        assert!(!matches(q, &[&line.replace("80ms", "heartbeat 80ms")])?);
+        assert!(!matches(q, &[&line.replace("order_gateway", "risk")])?);
+        // any line of the record can have the words, but the first has the
+        // target and the rest go along with it
+        let lines = ["level=error target=order_gateway msg=failed", "  timeout 80ms"];
+        assert!(matches(q, &lines)?);
+        // precedence, implicit AND, parentheses and case
+        assert!(matches("a OR b c", &["msg=a"])?);
+        assert!(!matches("(a OR b) c", &["msg=a"])?);
+        assert!(matches("NOT(a) OR /A/i", &["msg=a"])?);
+        assert!(!matches("NOT a", &["msg=a"])?);
+        // fields, as numbers if they are
+        assert!(matches("ms>100 ms<=200 id=7 id!=8", &["msg=x ms=150 id=7"])?);
+        assert!(!matches("ms>100", &["msg=x ms=90"])?);
+        assert!(matches("ts>=2024-03-01T14:02:00Z ts<2024-03-01T14:03:00Z", &[line])?);
+        assert!(matches("level:warn", &[line])? && !matches("level<warn", &[line])?);
+        // what to highlight
+        let q = Query::parse(r#"x OR "a b" AND NOT y AND /z+/i"#)?;
+        assert_eq!(q.highlight().map(Regex::as_str), Some("(?:x)|(?:a b)|(?:(?i)z+)"));
+        // and errors
+        let err = |q| Query::parse(q).unwrap_err().to_string();
+        assert_eq!(err("a AND (b"), "bad query at column 9");
+        assert!(err("a /b(/").starts_with("bad regex at column 4"));
+        assert_eq!(err("level>=loud"), "bad level at column 8: loud");
+        Ok(())
+    }
+}
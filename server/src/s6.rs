@@ -0,0 +1,91 @@
+//! s6-log directories: a `current` file being written to, plus archives
+//! named `@<tai64n>.s` (rotated cleanly) or `@<tai64n>.u` (rotated after
+//! an unclean shutdown), see https://skarnet.org/software/s6/s6-log.html
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// The name of the file s6-log is currently writing to
+pub const CURRENT: &str = "current";
+
+/// A TAI64N label, ordered chronologically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tai64N {
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+impl Tai64N {
+    /// Parse the 24 hex digits of a TAI64N label, with or without a leading @
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.strip_prefix('@').unwrap_or(s);
+        if s.len() != 24 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let secs = u64::from_str_radix(&s[..16], 16).ok()?;
+        let nanos = u32::from_str_radix(&s[16..], 16).ok()?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(Tai64N { secs, nanos })
+    }
+}
+
+/// Parse an archive file name, e.g. `@400000006a1b2c3d0a1b2c3d.s`
+pub fn parse_archive_name(name: &str) -> Option<Tai64N> {
+    let label = name.strip_suffix(".s").or_else(|| name.strip_suffix(".u"))?;
+    Tai64N::parse(label.strip_prefix('@')?)
+}
+
+/// List the archives in an s6-log directory, oldest first
+pub fn archives(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(label) = entry.file_name().to_str().and_then(parse_archive_name) {
+            archives.push((label, entry.path()));
+        }
+    }
+    archives.sort();
+    Ok(archives.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_archives() -> Result<()> {
+        assert_eq!(
+            parse_archive_name("@400000006a1b2c3d0a1b2c3d.s"),
+            Some(Tai64N { secs: 0x400000006a1b2c3d, nanos: 0x0a1b2c3d })
+        );
+        assert_eq!(parse_archive_name("@400000006a1b2c3d0a1b2c3d"), None);
+        assert_eq!(parse_archive_name("400000006a1b2c3d0a1b2c3d.s"), None);
+        assert_eq!(parse_archive_name("@400000006a1b2c3dffffffff.s"), None);
+        let dir = tempfile::tempdir()?;
+        for name in [
+            "@400000006a1b2c3e00000000.s",
+            "@400000006a1b2c3d00000001.u",
+            "@400000006a1b2c3d00000000.s",
+            "current",
+            "lock",
+            "state",
+        ] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        let names = archives(dir.path())?
+            .into_iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "@400000006a1b2c3d00000000.s",
+                "@400000006a1b2c3d00000001.u",
+                "@400000006a1b2c3e00000000.s"
+            ]
+        );
+        Ok(())
+    }
+}
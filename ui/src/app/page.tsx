@@ -16,6 +16,7 @@ type Logs = {
 
 type DisplayLine = {
   lln: number,
+  record: number,
   ll?: number | null,
   ts?: Date | null,
   spans: DisplaySpan[],
@@ -23,15 +24,62 @@ type DisplayLine = {
 
 type DisplaySpan = {
   text: string,
-  label: string
+  label: string,
+  style?: SpanStyle,
 }
 
+// colours are an xterm 256 colour palette index, or [r, g, b]
+type Color = number | [number, number, number];
+
+type SpanStyle = {
+  fg?: Color,
+  bg?: Color,
+  bold?: boolean,
+  underline?: boolean,
+};
+
+const ANSI_COLORS = [
+  "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+  "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+function cssColor(color?: Color): string | undefined {
+  if (color === undefined) {
+    return undefined;
+  } else if (Array.isArray(color)) {
+    return `rgb(${color[0]}, ${color[1]}, ${color[2]})`;
+  } else if (color < 16) {
+    return ANSI_COLORS[color];
+  } else if (color < 232) {
+    const steps = [0, 95, 135, 175, 215, 255];
+    const i = color - 16;
+    return `rgb(${steps[Math.floor(i / 36)]}, ${steps[Math.floor(i / 6) % 6]}, ${steps[i % 6]})`;
+  } else {
+    const v = 8 + (color - 232) * 10;
+    return `rgb(${v}, ${v}, ${v})`;
+  }
+}
+
+function cssStyle(style?: SpanStyle) {
+  return style && {
+    color: cssColor(style.fg),
+    backgroundColor: cssColor(style.bg),
+    fontWeight: style.bold ? "bold" : undefined,
+    textDecoration: style.underline ? "underline" : undefined,
+  };
+}
+
+type FilterMode = "regex" | "query";
+
 export default function Home() {
-  // filter
+  // filter, as a regex or in the query language
   const filterRef = useRef<HTMLInputElement>(null);
+  const filterModeRef = useRef<HTMLSelectElement>(null);
   const [filter, setFilter] = useState<string | null>(null);
+  const [filterMode, setFilterMode] = useState<FilterMode>("regex");
   const updateFilter = useCallback(() => {
     setFilter(filterRef.current?.value ?? null);
+    setFilterMode(filterModeRef.current?.value === "query" ? "query" : "regex");
   }, []);
   const clearFilter = useCallback(() => {
     if (filterRef.current) {
@@ -57,21 +105,40 @@ export default function Home() {
   });
   const nextRequestId = useRef(0);
   const inFlightRequests = useRef<{ [id: number]: string }>({});
+  // the logs request being shown, whose tail notifications to take
+  const subscription = useRef<number | null>(null);
   const [data, setData] = useState<Logs>({
     total_display_lines: 0,
     display_lines: [],
   });
+  // the last request's error, e.g. a bad filter
+  const [error, setError] = useState<string | null>(null);
   useEffect(() => {
     const data = lastMessage?.data;
     if (data) {
       const response = JSON.parse(data);
       const id = response["id"] as number;
       const method = inFlightRequests.current[id];
-      if (method) {
+      if (response["error"]) {
         delete inFlightRequests.current[id];
-        if (method === "list") {
-          setLogSets(response["result"]);
+        const column = response["error"].data?.column;
+        setError(response["error"].message + (column ? ` (column ${column})` : ""));
+      } else if (method) {
+        delete inFlightRequests.current[id];
+        if (method === "logs") {
+          subscription.current = response["result"].subscription;
+          setError(null);
+        } else if (method === "list") {
+          setLogSets(response["result"].map((logSet: { name: string }) => logSet.name));
+        } else if (method === "resize") {
+          const display_lines = response["result"].display_lines;
+          setData({
+            total_display_lines: display_lines.length,
+            display_lines,
+          });
         }
+      } else if (response["params"]?.subscription !== subscription.current) {
+        // left over from a subscription since replaced
       } else if (response["method"] === "tail") {
         const params = response["params"];
         setData(produce((data) => {
@@ -88,9 +155,37 @@ export default function Home() {
   const [ruler, { width: charWidth, height: charHeight }] = useMeasure();
   const cols = useDebounce(lineWidth && charWidth && Math.floor(lineWidth / charWidth), 300);
   const rows = useDebounce(height && charHeight && Math.floor(height / charHeight), 300);
-  // reload logs on resize
-  const reloadLog = useCallback((logset: string, cols: number, filter?: string | null) => {
+  // reload logs on a change of logset or filter, and just rewrap them on resize
+  const loaded = useRef<{
+    logset: string,
+    filter?: string | null,
+    filterMode: FilterMode,
+  } | null>(null);
+  const resizeLog = useCallback((cols: number) => {
+    const requestId = nextRequestId.current++;
+    const method = "resize";
+    inFlightRequests.current[requestId] = method;
+    sendMessage(JSON.stringify({
+      id: requestId,
+      method,
+      params: {subscription: subscription.current, cols},
+    }));
+  }, [sendMessage]);
+  const reloadLog = useCallback((
+    logset: string,
+    cols: number,
+    filter: string | null,
+    filterMode: FilterMode,
+  ) => {
     if (cols) {
+      if (subscription.current !== null) {
+        // as a notification, since there's nothing to do with the response
+        sendMessage(JSON.stringify({
+          method: "unsubscribe",
+          params: {subscription: subscription.current},
+        }));
+        subscription.current = null;
+      }
       const requestId = nextRequestId.current++;
       const method = "logs";
       inFlightRequests.current[requestId] = method;
@@ -100,16 +195,24 @@ export default function Home() {
         params: {
           logset,
           cols,
-          filter: filter && filter.length > 0 ? filter : undefined,
+          hanging_indent: true,
+          [filterMode === "query" ? "query" : "filter"]:
+            filter && filter.length > 0 ? filter : undefined,
         }
       }));
     }
   }, [sendMessage]);
   useEffect(() => {
     if (selectedLogSet && cols) {
-      reloadLog(selectedLogSet, cols, filter);
+      if (loaded.current?.logset === selectedLogSet && loaded.current?.filter === filter
+          && loaded.current?.filterMode === filterMode && subscription.current !== null) {
+        resizeLog(cols);
+      } else {
+        loaded.current = {logset: selectedLogSet, filter, filterMode};
+        reloadLog(selectedLogSet, cols, filter, filterMode);
+      }
     }
-  }, [reloadLog, selectedLogSet, cols, filter]);
+  }, [reloadLog, resizeLog, selectedLogSet, cols, filter, filterMode]);
   // list logsets on startup
   useEffect(() => {
     const requestId = nextRequestId.current++;
@@ -122,14 +225,16 @@ export default function Home() {
   }, [sendMessage]);
   const prevSelectedLogSet = usePrevious(selectedLogSet);
   const prevFilter = usePrevious(filter);
+  const prevFilterMode = usePrevious(filterMode);
   useEffect(() => {
-    if (selectedLogSet !== prevSelectedLogSet || filter !== prevFilter) {
+    if (selectedLogSet !== prevSelectedLogSet || filter !== prevFilter
+        || filterMode !== prevFilterMode) {
       setData({
         total_display_lines: 0,
         display_lines: [],
       });
     }
-  }, [selectedLogSet, prevSelectedLogSet, filter, prevFilter]);
+  }, [selectedLogSet, prevSelectedLogSet, filter, prevFilter, filterMode, prevFilterMode]);
   // react-window state
   const windowRef = useRef<FixedSizeList>(null);
   const [visibleStartIndex, setVisibleStartIndex] = useState(0);
@@ -161,9 +266,14 @@ export default function Home() {
             ))}
           </Listbox.Options>
         </Listbox>
-        <input ref={filterRef} className={styles.filter} type="text" placeholder="Filter logs by regex..."/>
+        <select ref={filterModeRef} defaultValue="regex">
+          <option value="regex">regex</option>
+          <option value="query">query</option>
+        </select>
+        <input ref={filterRef} className={styles.filter} type="text" placeholder="Filter logs by regex, or e.g. level>=warn AND /timeout/ as a query"/>
         <button onClick={() => updateFilter()}>Update</button>
         <button onClick={() => clearFilter()}>Clear</button>
+        {error && <span className={styles.error}>{error}</span>}
         <button
           hidden={isTailing}
           onClick={() => setIsTailing(true)}>
@@ -193,6 +303,7 @@ export default function Home() {
                     {(data.display_lines[index]?.spans || []).map((span, j) => (
                       <span
                         key={j}
+                        style={cssStyle(span.style)}
                         className={
                           styles[`span-${span.label}${span.label == 'level' 
                             ? ('-' + data.display_lines[index]?.ll ?? 5) 